@@ -0,0 +1,448 @@
+//! Crash log in flash memory
+//!
+//! Text written to a [`CrashLog`](struct.CrashLog.html) is appended to a ring
+//! of flash pages and survives resets. With two or more pages the older text
+//! is kept while the next page is erased. [`log_panic`](fn.log_panic.html)
+//! writes the panic message and a register snapshot from the panic handler:
+//!
+//! ```ignore
+//! const CRASH_LOG: FlashRegion = FlashRegion::new(124, 2);
+//!
+//! #[panic_handler]
+//! fn panic(info: &PanicInfo) -> ! {
+//!     unsafe { crashlog::log_panic(CRASH_LOG, info) };
+//!     loop {}
+//! }
+//! ```
+//!
+//! Flash is programmed in half-words, an odd trailing byte is held back until
+//! more text arrives or the log is flushed or dropped.
+//!
+//! On the next boot the text is handed out by
+//! [`CrashLog::read`](struct.CrashLog.html#method.read) and dropped by
+//! [`CrashLog::clear`](struct.CrashLog.html#method.clear).
+//!
+//! The log works on any storage implementing the `hal::storage` traits,
+//! `log_panic` uses [`storage::Flash`](../storage/struct.Flash.html).
+
+use core::cmp;
+use core::fmt::{self, Write};
+use core::mem;
+use core::panic::PanicInfo;
+use core::ptr;
+
+use cortex_m::interrupt;
+use cortex_m::peripheral::SCB;
+use cortex_m::register::{msp, psp};
+use hal::storage::{Address, ErasePage, MultiRead, MultiWrite, Page,
+                   StorageSize};
+use nb;
+
+use crate::flash::PROG;
+use crate::storage::{Event, Flash, FlashRegion};
+
+/// Never part of UTF-8 text, so it marks free space and pads odd writes
+const ERASED: u8 = 0xFF;
+const CHUNK_SIZE: usize = 64;
+
+/// Crash log error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error<E> {
+    /// Error of the underlying storage
+    Storage(E),
+    /// The region holds no pages
+    EmptyRegion,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Storage(e)
+    }
+}
+
+/// Ring log of text on flash pages
+pub struct CrashLog<S>
+    where S: MultiRead<u8, u32>
+              + MultiWrite<u16, u32, Error = <S as MultiRead<u8, u32>>::Error>
+              + ErasePage<u32, Error = <S as MultiRead<u8, u32>>::Error>
+              + StorageSize<u8, u32, Error = <S as MultiRead<u8, u32>>::Error>
+{
+    storage: S,
+    region: FlashRegion,
+    /// Address of the first page of the region
+    start: u32,
+    page_size: u32,
+    /// Offset of the next free half-word in the region
+    cursor: u32,
+    /// Odd trailing byte that goes to `cursor`
+    pending: Option<u8>,
+}
+
+impl<S, E> CrashLog<S>
+    where S: MultiRead<u8, u32, Error = E>
+              + MultiWrite<u16, u32, Error = E>
+              + ErasePage<u32, Error = E>
+              + StorageSize<u8, u32, Error = E>
+{
+    /// Appends to the text already stored in `region`
+    pub fn new(mut storage: S, region: FlashRegion) -> Result<Self, Error<E>> {
+        if region.is_empty() {
+            return Err(Error::EmptyRegion);
+        }
+        let base = nb::block!(storage.try_start_address())?.0;
+        let page_size = nb::block!(storage.try_page_size(Address(base)))?.0;
+        let start = base + region.first_page().0 * page_size;
+        let mut log = CrashLog { storage,
+                                 region,
+                                 start,
+                                 page_size,
+                                 cursor: 0,
+                                 pending: None };
+        log.mount()?;
+        Ok(log)
+    }
+
+    /// Flushes the log and releases the storage
+    pub fn free(mut self) -> S {
+        let _ = self.flush();
+        // NOTE(unsafe) `self` is forgotten right away, so the storage isn't
+        // duplicated
+        let storage = unsafe { ptr::read(&self.storage) };
+        mem::forget(self);
+        storage
+    }
+
+    /// Hands the stored text to `f` in chunks, oldest first
+    pub fn read<F>(&mut self, mut f: F) -> Result<(), Error<E>>
+        where F: FnMut(&[u8])
+    {
+        let pages = self.region.len();
+        let cursor_page = self.cursor / self.page_size;
+        for i in 1..=pages {
+            let page = (cursor_page + i) % pages;
+            let start = page * self.page_size;
+            let end = if page == cursor_page {
+                self.cursor
+            } else {
+                start + self.page_size
+            };
+
+            let mut offset = start;
+            while offset < end {
+                let mut buf = [0; CHUNK_SIZE];
+                let n = cmp::min(CHUNK_SIZE as u32, end - offset) as usize;
+                self.read_at(offset, &mut buf[..n])?;
+                // the rest of a page is free after the first erased
+                // half-word, single erased bytes are padding
+                let free = buf[..n].chunks(2).position(|h| h == [ERASED; 2]);
+                let len = free.map(|i| 2 * i).unwrap_or(n);
+                for text in buf[..len].split(|b| *b == ERASED) {
+                    if !text.is_empty() {
+                        f(text);
+                    }
+                }
+                if free.is_some() {
+                    break;
+                }
+                offset += n as u32;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether no text is stored
+    pub fn is_empty(&mut self) -> Result<bool, Error<E>> {
+        let mut empty = true;
+        self.read(|text| empty &= text.is_empty())?;
+        Ok(empty)
+    }
+
+    /// Erases all stored text
+    pub fn clear(&mut self) -> Result<(), Error<E>> {
+        for page in self.region.pages() {
+            nb::block!(self.storage.try_erase_page(page))?;
+        }
+        self.cursor = 0;
+        self.pending = None;
+        Ok(())
+    }
+
+    /// Appends `data`, erasing the oldest page when the ring wraps around
+    ///
+    /// An odd trailing byte is only programmed with the next write or
+    /// [`flush`](#method.flush).
+    pub fn write_bytes(&mut self, data: &[u8]) -> Result<(), Error<E>> {
+        let mut data = data;
+        if let Some(first) = self.pending {
+            let second = match data.split_first() {
+                Some((second, rest)) => {
+                    data = rest;
+                    *second
+                }
+                None => return Ok(()),
+            };
+            self.program(&[first, second])?;
+            self.pending = None;
+        }
+
+        let even = data.len() & !1;
+        self.program(&data[..even])?;
+        if even < data.len() {
+            self.pending = Some(data[even]);
+        }
+        Ok(())
+    }
+
+    /// Programs a byte held back by an odd write, padded with an erased byte
+    pub fn flush(&mut self) -> Result<(), Error<E>> {
+        if let Some(byte) = self.pending {
+            self.program(&[byte, ERASED])?;
+            self.pending = None;
+        }
+        Ok(())
+    }
+
+    /// Programs an even number of bytes at the cursor
+    fn program(&mut self, data: &[u8]) -> Result<(), Error<E>> {
+        let mut data = data;
+        while !data.is_empty() {
+            let page_end = (self.cursor / self.page_size + 1) * self.page_size;
+            let n = cmp::min(cmp::min(page_end - self.cursor,
+                                      CHUNK_SIZE as u32) as usize,
+                             data.len());
+            let mut buf = [0; CHUNK_SIZE / 2];
+            let buf = &mut buf[..n / 2];
+            for (halfword, pair) in buf.iter_mut().zip(data.chunks(2)) {
+                *halfword = u16::from(pair[0]) | u16::from(pair[1]) << 8;
+            }
+            let address = Address(self.start + self.cursor);
+            nb::block!(self.storage.try_write_slice(address, buf))?;
+            self.cursor += n as u32;
+            data = &data[n..];
+
+            if self.cursor == page_end {
+                self.cursor %= self.size();
+                self.erase_page_at(self.cursor)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u32 {
+        self.region.len() * self.page_size
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Error<E>> {
+        let address = Address(self.start + offset);
+        nb::block!(self.storage.try_read_slice(address, buf))?;
+        Ok(())
+    }
+
+    fn erase_page_at(&mut self, offset: u32) -> Result<(), Error<E>> {
+        let page = Page(self.region.first_page().0 + offset / self.page_size);
+        nb::block!(self.storage.try_erase_page(page))?;
+        Ok(())
+    }
+
+    /// Finds the first free half-word following the text
+    fn mount(&mut self) -> Result<(), Error<E>> {
+        let size = self.size();
+        let mut previous = [0; 2];
+        self.read_at(size - 2, &mut previous)?;
+
+        let mut offset = 0;
+        while offset < size {
+            let mut buf = [0; CHUNK_SIZE];
+            let n = cmp::min(CHUNK_SIZE as u32, size - offset) as usize;
+            self.read_at(offset, &mut buf[..n])?;
+            for (i, halfword) in buf[..n].chunks(2).enumerate() {
+                if halfword == [ERASED; 2] && previous != [ERASED; 2] {
+                    self.cursor = offset + 2 * i as u32;
+                    return Ok(());
+                }
+                previous.copy_from_slice(halfword);
+            }
+            offset += n as u32;
+        }
+
+        // either nothing was written or an erase was cut short
+        self.cursor = 0;
+        if previous != [ERASED; 2] {
+            self.erase_page_at(0)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S, E> Write for CrashLog<S>
+    where S: MultiRead<u8, u32, Error = E>
+              + MultiWrite<u16, u32, Error = E>
+              + ErasePage<u32, Error = E>
+              + StorageSize<u8, u32, Error = E>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl<S> Drop for CrashLog<S>
+    where S: MultiRead<u8, u32>
+              + MultiWrite<u16, u32, Error = <S as MultiRead<u8, u32>>::Error>
+              + ErasePage<u32, Error = <S as MultiRead<u8, u32>>::Error>
+              + StorageSize<u8, u32, Error = <S as MultiRead<u8, u32>>::Error>
+{
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Writes the panic message and a register snapshot to the crash log in
+/// `region`
+///
+/// Nothing is written if an erase or programming of the interrupted owner
+/// of the flash doesn't finish.
+///
+/// # Safety
+///
+/// Takes over the flash programming registers from their owner, only call
+/// it from the panic handler.
+pub unsafe fn log_panic(region: FlashRegion, info: &PanicInfo) {
+    interrupt::disable();
+
+    let mut flash = Flash::new(PROG::steal());
+    flash.unlisten(Event::EndOfOperation);
+    flash.unlisten(Event::Error);
+    if !flash.abort() {
+        return;
+    }
+
+    if let Ok(mut log) = CrashLog::new(flash, region) {
+        let scb = &*SCB::ptr();
+        let _ = writeln!(log, "{}", info);
+        let _ = writeln!(log,
+                         "MSP={:#010x} PSP={:#010x} CFSR={:#010x} \
+                          HFSR={:#010x} MMFAR={:#010x} BFAR={:#010x}",
+                         msp::read(),
+                         psp::read(),
+                         scb.cfsr.read(),
+                         scb.hfsr.read(),
+                         scb.mmfar.read(),
+                         scb.bfar.read());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_flash::{MockError, MockFlash, PAGE_SIZE, SIZE};
+
+    /// Two pages behind the first one of the mock
+    const REGION: FlashRegion = FlashRegion::new(1, 2);
+
+    fn mount(flash: &mut MockFlash) -> CrashLog<&mut MockFlash> {
+        CrashLog::new(flash, REGION).unwrap()
+    }
+
+    /// Stored text, concatenated
+    fn text(log: &mut CrashLog<&mut MockFlash>) -> ([u8; SIZE], usize) {
+        let mut buf = [0; SIZE];
+        let mut len = 0;
+        log.read(|text| {
+               buf[len..len + text.len()].copy_from_slice(text);
+               len += text.len();
+           })
+           .unwrap();
+        (buf, len)
+    }
+
+    fn assert_text(log: &mut CrashLog<&mut MockFlash>, expected: &[u8]) {
+        let (buf, len) = text(log);
+        assert_eq!(&buf[..len], expected);
+    }
+
+    #[test]
+    fn empty() {
+        let mut flash = MockFlash::new();
+        let mut log = mount(&mut flash);
+        assert!(log.is_empty().unwrap());
+        assert_eq!(log.cursor, 0);
+    }
+
+    #[test]
+    fn empty_region() {
+        let mut flash = MockFlash::new();
+        assert!(matches!(CrashLog::new(&mut flash, FlashRegion::new(1, 0)),
+                         Err(Error::EmptyRegion)));
+    }
+
+    #[test]
+    fn mount_after_partial_write() {
+        let mut flash = MockFlash::new();
+        {
+            let mut log = mount(&mut flash);
+            log.write_bytes(b"hello world!").unwrap();
+            // the power is cut after two of five half-words
+            log.storage.budget = Some(2);
+            assert_eq!(log.write_bytes(b"0123456789"),
+                       Err(Error::Storage(MockError::PowerLoss)));
+        }
+        flash.budget = None;
+
+        let mut log = mount(&mut flash);
+        assert_eq!(log.cursor, 16);
+        assert_text(&mut log, b"hello world!0123");
+        log.write_bytes(b"x").unwrap();
+        log.flush().unwrap();
+        assert_text(&mut log, b"hello world!0123x");
+    }
+
+    #[test]
+    fn ring_wrap_erases_oldest_page() {
+        let mut flash = MockFlash::new();
+        {
+            let mut log = mount(&mut flash);
+            log.write_bytes(&[b'a'; PAGE_SIZE as usize]).unwrap();
+            log.write_bytes(&[b'b'; PAGE_SIZE as usize]).unwrap();
+            log.write_bytes(b"cc").unwrap();
+        }
+
+        // the first page of the region held the 'a's
+        let first = PAGE_SIZE as usize;
+        assert_eq!(&flash.memory[first..first + 3], b"cc\xFF");
+        assert!(flash.memory[first + 2..2 * first].iter().all(|b| *b == 0xFF));
+
+        let mut log = mount(&mut flash);
+        assert_eq!(log.cursor, 2);
+        let (buf, len) = text(&mut log);
+        assert_eq!(len, PAGE_SIZE as usize + 2);
+        assert!(buf[..PAGE_SIZE as usize].iter().all(|b| *b == b'b'));
+        assert_eq!(&buf[PAGE_SIZE as usize..len], b"cc");
+    }
+
+    #[test]
+    fn drop_flushes_pending_byte() {
+        let mut flash = MockFlash::new();
+        {
+            let mut log = mount(&mut flash);
+            log.write_bytes(b"abc").unwrap();
+            assert_eq!(log.pending, Some(b'c'));
+        }
+
+        let first = PAGE_SIZE as usize;
+        assert_eq!(&flash.memory[first..first + 4], b"abc\xFF");
+        let mut log = mount(&mut flash);
+        assert_eq!(log.cursor, 4);
+        assert_text(&mut log, b"abc");
+    }
+
+    #[test]
+    fn clear() {
+        let mut flash = MockFlash::new();
+        let mut log = mount(&mut flash);
+        log.write_bytes(b"boom").unwrap();
+        log.clear().unwrap();
+        assert!(log.is_empty().unwrap());
+        drop(log);
+        assert!(flash.memory.iter().all(|b| *b == 0xFF));
+    }
+}
@@ -0,0 +1,61 @@
+//! Cyclic Redundancy Check (CRC) calculation unit
+
+use core::ptr;
+
+use crate::pac::CRC;
+use crate::rcc::AHB;
+
+/// Extension trait to constrain the CRC peripheral
+pub trait CrcExt {
+    /// Enables the CRC unit, set up for the CRC-32 of zlib and Ethernet
+    fn constrain(self, ahb: &mut AHB) -> Crc;
+}
+
+impl CrcExt for CRC {
+    fn constrain(self, ahb: &mut AHB) -> Crc {
+        ahb.enr().modify(|_, w| w.crcen().enabled());
+
+        // REV_IN: bit reversal per input byte
+        // REV_OUT: bit reversed output
+        // the CRC-32 polynomial and initial value are the reset values
+        self.cr
+            .write(|w| unsafe { w.rev_in().bits(0b01) }.rev_out().set_bit());
+
+        let mut crc = Crc { crc: self };
+        crc.reset();
+        crc
+    }
+}
+
+/// CRC-32 calculation unit
+pub struct Crc {
+    crc: CRC,
+}
+
+impl Crc {
+    /// Starts a new calculation
+    pub fn reset(&mut self) {
+        self.crc.cr.modify(|_, w| w.reset().set_bit());
+    }
+
+    /// Feeds `data` into the calculation
+    pub fn feed(&mut self, data: &[u8]) {
+        for byte in data {
+            // NOTE(write_volatile) a byte write feeds a single byte (the
+            // svd2rust API only allows writing a word)
+            unsafe {
+                ptr::write_volatile(&self.crc.dr as *const _ as *mut u8, *byte)
+            }
+        }
+    }
+
+    /// Returns the CRC-32 of the bytes fed since the last reset
+    pub fn result(&self) -> u32 {
+        self.crc.dr.read().bits() ^ 0xFFFF_FFFF
+    }
+
+    /// Releases the CRC peripheral
+    pub fn free(self) -> CRC {
+        self.crc
+    }
+}
@@ -4,8 +4,9 @@ use cast::u32;
 use cortex_m::peripheral::syst::SystClkSource;
 use cortex_m::peripheral::SYST;
 
-use crate::rcc::Clocks;
+use crate::rcc::{Clocks, Reclock};
 use hal::blocking::delay::{DelayMs, DelayUs};
+use void::Void;
 
 /// System timer (SysTick) as a delay provider
 pub struct Delay {
@@ -27,25 +28,42 @@ impl Delay {
     }
 }
 
+impl Reclock for Delay {
+    type Error = Void;
+
+    fn reclock(&mut self, clocks: Clocks) -> Result<(), Self::Error> {
+        self.clocks = clocks;
+        Ok(())
+    }
+}
+
 impl DelayMs<u32> for Delay {
+    type Error = Void;
+
     fn try_delay_ms(&mut self, ms: u32) -> Result<(), Self::Error> {
-        Ok(self.delay_us(ms * 1_000))
+        self.try_delay_us(ms * 1_000)
     }
 }
 
 impl DelayMs<u16> for Delay {
+    type Error = Void;
+
     fn try_delay_ms(&mut self, ms: u16) -> Result<(), Self::Error> {
-        Ok(self.delay_ms(u32(ms)))
+        self.try_delay_ms(u32(ms))
     }
 }
 
 impl DelayMs<u8> for Delay {
+    type Error = Void;
+
     fn try_delay_ms(&mut self, ms: u8) -> Result<(), Self::Error> {
-        Ok(self.delay_ms(u32(ms)))
+        self.try_delay_ms(u32(ms))
     }
 }
 
 impl DelayUs<u32> for Delay {
+    type Error = Void;
+
     fn try_delay_us(&mut self, us: u32) -> Result<(), Self::Error> {
         let rvr = us * (self.clocks.sysclk().0 / 1_000_000);
 
@@ -57,18 +75,24 @@ impl DelayUs<u32> for Delay {
 
         while !self.syst.has_wrapped() {}
 
-        Ok(self.syst.disable_counter())
+        self.syst.disable_counter();
+
+        Ok(())
     }
 }
 
 impl DelayUs<u16> for Delay {
+    type Error = Void;
+
     fn try_delay_us(&mut self, us: u16) -> Result<(), Self::Error> {
-        Ok(self.delay_us(u32(us)))
+        self.try_delay_us(u32(us))
     }
 }
 
 impl DelayUs<u8> for Delay {
+    type Error = Void;
+
     fn try_delay_us(&mut self, us: u8) -> Result<(), Self::Error> {
-        Ok(self.delay_us(u32(us)))
+        self.try_delay_us(u32(us))
     }
 }
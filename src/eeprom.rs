@@ -0,0 +1,562 @@
+//! EEPROM emulation on flash pages
+//!
+//! Variables are identified by 16-bit virtual addresses and appended as
+//! (value, address) entries to the active page. When it's full, the latest
+//! values are compacted into the next page of the region and the old page is
+//! erased, so the wear is spread over all pages.
+//!
+//! Every page starts with a status half-word:
+//!
+//! - `ERASED`: unused
+//! - `RECEIVE`: the target of an interrupted page swap
+//! - `VALID`: the active page
+//!
+//! The status markers and the order of the page swap steps let
+//! [`Eeprom::new`](struct.Eeprom.html#method.new) pick up the data after a
+//! power loss at any point.
+//!
+//! The module is generic over the `hal::storage` traits and only needs
+//! [`storage::Flash`](../storage/struct.Flash.html) on the target.
+
+use hal::storage::{Address, ErasePage, MultiRead, MultiWrite, Page,
+                   StorageSize};
+use nb;
+
+const ERASED: u16 = 0xFFFF;
+const RECEIVE: u16 = 0xEEEE;
+const VALID: u16 = 0x0000;
+/// Status half-word, padded to an entry
+const HEADER_SIZE: u32 = 4;
+/// Value half-word followed by the address half-word
+const ENTRY_SIZE: u32 = 4;
+
+/// EEPROM emulation error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error<E> {
+    /// Error of the underlying storage
+    Storage(E),
+    /// Virtual address 0xFFFF marks free entries
+    InvalidAddress,
+    /// At least two pages are needed
+    TooFewPages,
+    /// More variables than fit into a page
+    Full,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Storage(e)
+    }
+}
+
+/// Key/value store over a region of flash pages
+pub struct Eeprom<S> {
+    storage: S,
+    /// Address of the first page of the region
+    start: u32,
+    /// Index of the first page of the region in the storage
+    first_page: u32,
+    pages: u32,
+    page_size: u32,
+    /// Page of the region holding the current values
+    active: u32,
+    /// Offset of the next free entry in the active page
+    next: u32,
+}
+
+impl<S, E> Eeprom<S>
+    where S: MultiRead<u8, u32, Error = E>
+              + MultiWrite<u16, u32, Error = E>
+              + ErasePage<u32, Error = E>
+              + StorageSize<u8, u32, Error = E>
+{
+    /// Uses `pages` pages from `first_page` on, formatting them if they
+    /// hold no valid data and finishing an interrupted page swap
+    pub fn new(mut storage: S,
+               first_page: u32,
+               pages: u32)
+               -> Result<Self, Error<E>> {
+        if pages < 2 {
+            return Err(Error::TooFewPages);
+        }
+        let base = nb::block!(storage.try_start_address())?.0;
+        let page_size = nb::block!(storage.try_page_size(Address(base)))?.0;
+
+        let mut eeprom = Eeprom { storage,
+                                  start: base + first_page * page_size,
+                                  first_page,
+                                  pages,
+                                  page_size,
+                                  active: 0,
+                                  next: HEADER_SIZE };
+        eeprom.mount()?;
+        Ok(eeprom)
+    }
+
+    /// Releases the storage
+    pub fn free(self) -> S {
+        self.storage
+    }
+
+    /// Erases all pages, dropping all variables
+    pub fn format(&mut self) -> Result<(), Error<E>> {
+        for page in 0..self.pages {
+            self.erase(page)?;
+        }
+        self.write_u16(self.page_address(0), VALID)?;
+        self.active = 0;
+        self.next = HEADER_SIZE;
+        Ok(())
+    }
+
+    /// Returns the value of the variable at `address`, `None` if it was
+    /// never written
+    pub fn read(&mut self, address: u16) -> Result<Option<u16>, Error<E>> {
+        if address == ERASED {
+            return Err(Error::InvalidAddress);
+        }
+        self.find(self.active, self.next, address)
+    }
+
+    /// Writes the variable at `address`
+    pub fn write(&mut self,
+                 address: u16,
+                 value: u16)
+                 -> Result<(), Error<E>> {
+        if self.read(address)? == Some(value) {
+            return Ok(());
+        }
+        if self.next + ENTRY_SIZE > self.page_size {
+            return self.swap(Some((address, value)));
+        }
+        let active = self.active;
+        self.append(active, address, value)
+    }
+
+    fn page_address(&self, page: u32) -> u32 {
+        self.start + page * self.page_size
+    }
+
+    fn read_u16(&mut self, address: u32) -> Result<u16, Error<E>> {
+        let mut buf = [0; 2];
+        nb::block!(self.storage.try_read_slice(Address(address), &mut buf))?;
+        Ok(u16::from(buf[0]) | u16::from(buf[1]) << 8)
+    }
+
+    fn write_u16(&mut self, address: u32, value: u16) -> Result<(), Error<E>> {
+        let mut buf = [value];
+        nb::block!(self.storage.try_write_slice(Address(address), &mut buf))?;
+        Ok(())
+    }
+
+    fn erase(&mut self, page: u32) -> Result<(), Error<E>> {
+        let page = Page(self.first_page + page);
+        nb::block!(self.storage.try_erase_page(page))?;
+        Ok(())
+    }
+
+    fn status(&mut self, page: u32) -> Result<u16, Error<E>> {
+        self.read_u16(self.page_address(page))
+    }
+
+    /// Reads the entry at `offset` of `page` as (address, value)
+    fn entry(&mut self,
+             page: u32,
+             offset: u32)
+             -> Result<(u16, u16), Error<E>> {
+        let entry = self.page_address(page) + offset;
+        let value = self.read_u16(entry)?;
+        let address = self.read_u16(entry + 2)?;
+        Ok((address, value))
+    }
+
+    /// Offset of the first free entry of `page`
+    fn end(&mut self, page: u32) -> Result<u32, Error<E>> {
+        // entries are appended, the last used one ends the page
+        let mut end = HEADER_SIZE;
+        let mut offset = HEADER_SIZE;
+        while offset + ENTRY_SIZE <= self.page_size {
+            if self.entry(page, offset)? != (ERASED, ERASED) {
+                end = offset + ENTRY_SIZE;
+            }
+            offset += ENTRY_SIZE;
+        }
+        Ok(end)
+    }
+
+    /// Latest value of `address` in the entries of `page` before `end`
+    fn find(&mut self,
+            page: u32,
+            end: u32,
+            address: u16)
+            -> Result<Option<u16>, Error<E>> {
+        let mut offset = end;
+        while offset > HEADER_SIZE {
+            offset -= ENTRY_SIZE;
+            let (a, value) = self.entry(page, offset)?;
+            if a == address {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    fn append(&mut self,
+              page: u32,
+              address: u16,
+              value: u16)
+              -> Result<(), Error<E>> {
+        let entry = self.page_address(page) + self.next;
+        // the entry only counts once its address is written
+        self.write_u16(entry, value)?;
+        self.write_u16(entry + 2, address)?;
+        self.next += ENTRY_SIZE;
+        Ok(())
+    }
+
+    /// Whether the entry at `offset` holds the latest value of its variable
+    fn is_latest(&mut self,
+                 page: u32,
+                 end: u32,
+                 offset: u32)
+                 -> Result<bool, Error<E>> {
+        let (address, _) = self.entry(page, offset)?;
+        if address == ERASED {
+            // torn write, the value landed but not the address
+            return Ok(false);
+        }
+        let mut later = offset + ENTRY_SIZE;
+        while later < end {
+            if self.entry(page, later)?.0 == address {
+                return Ok(false);
+            }
+            later += ENTRY_SIZE;
+        }
+        Ok(true)
+    }
+
+    /// Moves the latest values to the next page, together with `pending`
+    fn swap(&mut self, pending: Option<(u16, u16)>) -> Result<(), Error<E>> {
+        let old = self.active;
+        let old_end = self.next;
+        let capacity = (self.page_size - HEADER_SIZE) / ENTRY_SIZE;
+
+        // refuse before anything is touched
+        let mut count = 0;
+        let mut offset = HEADER_SIZE;
+        while offset < old_end {
+            if self.is_latest(old, old_end, offset)? {
+                let (address, _) = self.entry(old, offset)?;
+                if pending.map(|(a, _)| a) != Some(address) {
+                    count += 1;
+                }
+            }
+            offset += ENTRY_SIZE;
+        }
+        if pending.is_some() {
+            count += 1;
+        }
+        if count > capacity {
+            return Err(Error::Full);
+        }
+
+        let new = (old + 1) % self.pages;
+        self.erase(new)?;
+        self.write_u16(self.page_address(new), RECEIVE)?;
+        self.active = new;
+        self.next = HEADER_SIZE;
+        if let Some((address, value)) = pending {
+            self.append(new, address, value)?;
+        }
+        self.transfer(old, old_end)
+    }
+
+    /// Copies the latest values of `old` missing in the active page, erases
+    /// `old` and marks the active page valid
+    ///
+    /// Can be repeated after a power loss, values already copied are kept.
+    fn transfer(&mut self, old: u32, old_end: u32) -> Result<(), Error<E>> {
+        let new = self.active;
+        let mut offset = HEADER_SIZE;
+        while offset < old_end {
+            if self.is_latest(old, old_end, offset)? {
+                let (address, value) = self.entry(old, offset)?;
+                let next = self.next;
+                if self.find(new, next, address)?.is_none() {
+                    self.append(new, address, value)?;
+                }
+            }
+            offset += ENTRY_SIZE;
+        }
+
+        self.erase(old)?;
+        // VALID can be programmed over RECEIVE
+        self.write_u16(self.page_address(new), VALID)
+    }
+
+    /// Recovers the active page from the status markers
+    fn mount(&mut self) -> Result<(), Error<E>> {
+        let mut valid = None;
+        let mut receive = None;
+        for page in 0..self.pages {
+            match self.status(page)? {
+                VALID if valid.is_none() => valid = Some(page),
+                RECEIVE if receive.is_none() => receive = Some(page),
+                ERASED => {}
+                // duplicates and garbage from an interrupted erase
+                _ => self.erase(page)?,
+            }
+        }
+
+        match (valid, receive) {
+            (Some(page), None) => {
+                self.active = page;
+                self.next = self.end(page)?;
+                Ok(())
+            }
+            // swap interrupted while copying
+            (Some(old), Some(new)) => {
+                let old_end = self.end(old)?;
+                self.active = new;
+                self.next = self.end(new)?;
+                self.transfer(old, old_end)
+            }
+            // swap interrupted after erasing the old page
+            (None, Some(new)) => {
+                self.active = new;
+                self.next = self.end(new)?;
+                self.write_u16(self.page_address(new), VALID)
+            }
+            (None, None) => self.format(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_flash::{MockError, MockFlash, PAGE_SIZE};
+
+    /// Entries per page
+    const CAPACITY: u32 = (PAGE_SIZE - HEADER_SIZE) / ENTRY_SIZE;
+
+    fn mount(flash: MockFlash) -> Eeprom<MockFlash> {
+        Eeprom::new(flash, 1, 2).unwrap()
+    }
+
+    /// Remounts `eeprom` as after a reset, with the power back on
+    fn remount(eeprom: Eeprom<MockFlash>) -> Eeprom<MockFlash> {
+        let mut flash = eeprom.free();
+        flash.budget = None;
+        mount(flash)
+    }
+
+    /// Eeprom whose active page is full, variable `k` of 0..5 holds `k + 10`
+    fn full_page() -> Eeprom<MockFlash> {
+        let mut eeprom = mount(MockFlash::new());
+        for i in 0..CAPACITY as u16 {
+            eeprom.write(i % 5, i).unwrap();
+        }
+        assert_eq!(eeprom.next, PAGE_SIZE);
+        eeprom
+    }
+
+    fn assert_full_page_values(eeprom: &mut Eeprom<MockFlash>) {
+        for k in 0..5 {
+            assert_eq!(eeprom.read(k).unwrap(), Some(k + 10));
+        }
+    }
+
+    /// Cuts the power after `ops` programmed half-words and erased pages
+    /// of a write that swaps pages
+    fn swap_with_power_loss(ops: u32) -> (Eeprom<MockFlash>, bool) {
+        let mut eeprom = full_page();
+        eeprom.storage.budget = Some(ops);
+        let done = match eeprom.write(100, 7) {
+            Ok(()) => true,
+            Err(Error::Storage(MockError::PowerLoss)) => false,
+            Err(e) => panic!("{:?}", e),
+        };
+        (remount(eeprom), done)
+    }
+
+    #[test]
+    fn write_read() {
+        let mut eeprom = mount(MockFlash::new());
+        assert_eq!(eeprom.read(1).unwrap(), None);
+        eeprom.write(1, 0x1234).unwrap();
+        eeprom.write(2, 0xFFFF).unwrap();
+        eeprom.write(1, 0x5678).unwrap();
+        assert_eq!(eeprom.read(1).unwrap(), Some(0x5678));
+        assert_eq!(eeprom.read(2).unwrap(), Some(0xFFFF));
+        assert_eq!(eeprom.read(ERASED), Err(Error::InvalidAddress));
+
+        let mut eeprom = remount(eeprom);
+        assert_eq!(eeprom.read(1).unwrap(), Some(0x5678));
+        assert_eq!(eeprom.read(2).unwrap(), Some(0xFFFF));
+    }
+
+    #[test]
+    fn unchanged_value_is_not_appended() {
+        let mut eeprom = mount(MockFlash::new());
+        eeprom.write(1, 5).unwrap();
+        let next = eeprom.next;
+        eeprom.write(1, 5).unwrap();
+        assert_eq!(eeprom.next, next);
+    }
+
+    #[test]
+    fn too_few_pages() {
+        assert!(matches!(Eeprom::new(MockFlash::new(), 0, 1),
+                         Err(Error::TooFewPages)));
+    }
+
+    #[test]
+    fn page_swap() {
+        let mut eeprom = full_page();
+        eeprom.write(100, 7).unwrap();
+        assert_eq!(eeprom.active, 1);
+        // five latest values and the new one
+        assert_eq!(eeprom.next, HEADER_SIZE + 6 * ENTRY_SIZE);
+        assert_full_page_values(&mut eeprom);
+        assert_eq!(eeprom.read(100).unwrap(), Some(7));
+        assert_eq!(eeprom.status(0).unwrap(), ERASED);
+        assert_eq!(eeprom.status(1).unwrap(), VALID);
+
+        let mut eeprom = remount(eeprom);
+        assert_eq!(eeprom.active, 1);
+        assert_full_page_values(&mut eeprom);
+        assert_eq!(eeprom.read(100).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn swap_wraps_around() {
+        let mut eeprom = mount(MockFlash::new());
+        for i in 0..3 * CAPACITY as u16 {
+            eeprom.write(i % 2, i).unwrap();
+        }
+        assert_eq!(eeprom.read(0).unwrap(), Some(3 * CAPACITY as u16 - 1));
+        assert_eq!(eeprom.read(1).unwrap(), Some(3 * CAPACITY as u16 - 2));
+        // pages outside of the region are left alone
+        let flash = eeprom.free();
+        assert!(flash.memory[..PAGE_SIZE as usize].iter().all(|b| *b == 0xFF));
+        assert!(flash.memory[3 * PAGE_SIZE as usize..].iter()
+                                                      .all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn full() {
+        let mut eeprom = mount(MockFlash::new());
+        for k in 0..CAPACITY as u16 {
+            eeprom.write(k, k).unwrap();
+        }
+        assert_eq!(eeprom.write(CAPACITY as u16, 0), Err(Error::Full));
+        // nothing was touched
+        assert_eq!(eeprom.active, 0);
+        for k in 0..CAPACITY as u16 {
+            assert_eq!(eeprom.read(k).unwrap(), Some(k));
+        }
+        // known variables can still be changed
+        eeprom.write(0, 100).unwrap();
+        assert_eq!(eeprom.read(0).unwrap(), Some(100));
+    }
+
+    #[test]
+    fn power_loss_after_erasing_the_next_page() {
+        let (mut eeprom, done) = swap_with_power_loss(1);
+        assert!(!done);
+        assert_eq!(eeprom.active, 0);
+        assert_full_page_values(&mut eeprom);
+        assert_eq!(eeprom.read(100).unwrap(), None);
+    }
+
+    #[test]
+    fn power_loss_after_receive() {
+        // erase and RECEIVE went through, the old page is still valid
+        let (mut eeprom, done) = swap_with_power_loss(2);
+        assert!(!done);
+        assert_eq!(eeprom.active, 1);
+        assert_eq!(eeprom.status(0).unwrap(), ERASED);
+        assert_eq!(eeprom.status(1).unwrap(), VALID);
+        assert_full_page_values(&mut eeprom);
+        assert_eq!(eeprom.read(100).unwrap(), None);
+    }
+
+    #[test]
+    fn power_loss_while_copying() {
+        // RECEIVE, the new value and two of the five values copied
+        let (mut eeprom, done) = swap_with_power_loss(2 + 2 + 2 * 2);
+        assert!(!done);
+        assert_eq!(eeprom.active, 1);
+        assert_eq!(eeprom.next, HEADER_SIZE + 6 * ENTRY_SIZE);
+        assert_full_page_values(&mut eeprom);
+        assert_eq!(eeprom.read(100).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn power_loss_after_erasing_the_old_page() {
+        // everything but VALID went through
+        let (mut eeprom, done) = swap_with_power_loss(2 + 2 + 5 * 2 + 1);
+        assert!(!done);
+        assert_eq!(eeprom.status(0).unwrap(), ERASED);
+        assert_eq!(eeprom.status(1).unwrap(), VALID);
+        assert_full_page_values(&mut eeprom);
+        assert_eq!(eeprom.read(100).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn power_loss_at_every_swap_step() {
+        let mut ops = 0;
+        loop {
+            let (mut eeprom, done) = swap_with_power_loss(ops);
+            assert_full_page_values(&mut eeprom);
+            match eeprom.read(100).unwrap() {
+                Some(7) => {}
+                None => assert!(!done),
+                value => panic!("{:?} after {} operations", value, ops),
+            }
+            // still usable
+            eeprom.write(101, 1).unwrap();
+            assert_eq!(eeprom.read(101).unwrap(), Some(1));
+            if done {
+                break;
+            }
+            ops += 1;
+        }
+    }
+
+    #[test]
+    fn torn_entry() {
+        let mut eeprom = mount(MockFlash::new());
+        eeprom.write(1, 10).unwrap();
+        // the value lands, the address doesn't
+        eeprom.storage.budget = Some(1);
+        assert_eq!(eeprom.write(1, 20),
+                   Err(Error::Storage(MockError::PowerLoss)));
+
+        let mut eeprom = remount(eeprom);
+        assert_eq!(eeprom.read(1).unwrap(), Some(10));
+        // the torn entry is skipped
+        assert_eq!(eeprom.next, HEADER_SIZE + 2 * ENTRY_SIZE);
+        eeprom.write(1, 20).unwrap();
+        eeprom.write(2, 30).unwrap();
+        assert_eq!(eeprom.read(1).unwrap(), Some(20));
+        assert_eq!(eeprom.read(2).unwrap(), Some(30));
+    }
+
+    #[test]
+    fn torn_entry_is_dropped_by_swap() {
+        let mut eeprom = mount(MockFlash::new());
+        eeprom.write(1, 10).unwrap();
+        eeprom.storage.budget = Some(1);
+        assert!(eeprom.write(1, 20).is_err());
+
+        let mut eeprom = remount(eeprom);
+        for i in 0..CAPACITY as u16 {
+            eeprom.write(2, i).unwrap();
+        }
+        assert_eq!(eeprom.active, 1);
+        assert_eq!(eeprom.read(1).unwrap(), Some(10));
+        assert_eq!(eeprom.read(2).unwrap(), Some(CAPACITY as u16 - 1));
+    }
+}
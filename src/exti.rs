@@ -232,7 +232,7 @@ macro_rules! gen_exti {
 gen_exti!([(EXTI0, EXTI0, 0),
            (EXTI1, EXTI1, 1),
            (EXTI2, EXTI2_TSC, 2),
-           (EXTI3, EXTI3, 2),
+           (EXTI3, EXTI3, 3),
            (EXTI4, EXTI4, 4),
            (EXTI13, EXTI15_10, 13),]);
 
@@ -245,5 +245,6 @@ fn bits_of_gpio_group(group: &gpio::Group) -> u8 {
         gpio::Group::E => 0b0100,
         gpio::Group::F => 0b0101,
         gpio::Group::G => 0b0110,
+        gpio::Group::H => 0b0111,
     }
 }
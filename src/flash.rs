@@ -1,6 +1,9 @@
 //! Flash memory
 
+use cortex_m::asm;
+
 use crate::pac::{flash, FLASH};
+use crate::rcc::{self, Clocks};
 
 /// Extension trait to constrain the FLASH peripheral
 pub trait FlashExt {
@@ -11,7 +14,8 @@ pub trait FlashExt {
 
 impl FlashExt for FLASH {
     fn constrain(self) -> Parts {
-        Parts { acr: ACR { _0: () } }
+        Parts { acr: ACR { _0: () },
+                prog: PROG { _0: () } }
     }
 }
 
@@ -19,6 +23,8 @@ impl FlashExt for FLASH {
 pub struct Parts {
     /// Opaque ACR register
     pub acr: ACR,
+    /// Opaque programming registers
+    pub prog: PROG,
 }
 
 /// Opaque ACR register
@@ -31,4 +37,387 @@ impl ACR {
         // NOTE(unsafe) this proxy grants exclusive access to this register
         unsafe { &(*FLASH::ptr()).acr }
     }
+
+    fn read(&self) -> flash::acr::R {
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { (*FLASH::ptr()).acr.read() }
+    }
+
+    /// Returns the number of flash wait states
+    pub fn latency(&self) -> u8 {
+        self.read().latency().bits()
+    }
+
+    /// Sets the number of flash wait states
+    ///
+    /// `clocks` needs 0 wait states up to 24 MHz SYSCLK, 1 up to 48 MHz and
+    /// 2 above.
+    pub fn set_latency(&mut self,
+                       wait_states: u8,
+                       clocks: &Clocks)
+                       -> Result<(), AcrError> {
+        if wait_states > 2 {
+            return Err(AcrError::LatencyOutOfRange);
+        }
+        if wait_states < rcc::latency_bits(clocks.sysclk().0) {
+            return Err(AcrError::LatencyTooLow);
+        }
+        self.acr()
+            .modify(|_, w| unsafe { w.latency().bits(wait_states) });
+        Ok(())
+    }
+
+    /// Returns whether the prefetch buffer is enabled (PRFTBE)
+    pub fn is_prefetch_enabled(&self) -> bool {
+        self.read().prftbe().bit_is_set()
+    }
+
+    /// Returns whether the prefetch buffer is active (PRFTBS)
+    pub fn is_prefetch_active(&self) -> bool {
+        self.read().prftbs().bit_is_set()
+    }
+
+    /// Enables or disables the prefetch buffer
+    ///
+    /// It can only be switched while SYSCLK is below 24 MHz and HCLK isn't
+    /// divided.
+    pub fn set_prefetch(&mut self,
+                        enable: bool,
+                        clocks: &Clocks)
+                        -> Result<(), AcrError> {
+        if clocks.sysclk().0 >= 24_000_000
+           || clocks.hclk().0 != clocks.sysclk().0
+        {
+            return Err(AcrError::PrefetchSwitch);
+        }
+        self.acr().modify(|_, w| w.prftbe().bit(enable));
+        Ok(())
+    }
+
+    /// Returns whether half-cycle flash access is enabled (HLFCYA)
+    pub fn is_half_cycle_enabled(&self) -> bool {
+        self.read().hlfcya().bit_is_set()
+    }
+
+    /// Enables or disables half-cycle flash access
+    ///
+    /// Half-cycle access is only allowed with HCLK below 8 MHz, derived
+    /// from HSI or HSE without the PLL.
+    pub fn set_half_cycle(&mut self,
+                          enable: bool,
+                          clocks: &Clocks)
+                          -> Result<(), AcrError> {
+        if enable
+           && (clocks.pll_source().is_some()
+               || clocks.hclk().0 >= 8_000_000)
+        {
+            return Err(AcrError::HalfCycleUnavailable);
+        }
+        self.acr().modify(|_, w| w.hlfcya().bit(enable));
+        Ok(())
+    }
+}
+
+/// Flash access configuration error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AcrError {
+    /// More than 2 wait states
+    LatencyOutOfRange,
+    /// Fewer wait states than SYSCLK needs
+    LatencyTooLow,
+    /// Prefetch buffer switched at or above 24 MHz SYSCLK or with divided
+    /// HCLK
+    PrefetchSwitch,
+    /// Half-cycle access needs HCLK below 8 MHz from HSI or HSE
+    HalfCycleUnavailable,
+}
+
+/// Opaque programming registers (KEYR, SR, CR, AR)
+pub struct PROG {
+    _0: (),
+}
+
+impl PROG {
+    /// Conjures the programming registers out of thin air
+    pub(crate) unsafe fn steal() -> Self {
+        PROG { _0: () }
+    }
+
+    pub(crate) fn keyr(&mut self) -> &flash::KEYR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*FLASH::ptr()).keyr }
+    }
+
+    pub(crate) fn sr(&mut self) -> &flash::SR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*FLASH::ptr()).sr }
+    }
+
+    pub(crate) fn cr(&mut self) -> &flash::CR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*FLASH::ptr()).cr }
+    }
+
+    pub(crate) fn ar(&mut self) -> &flash::AR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*FLASH::ptr()).ar }
+    }
+}
+
+pub(crate) const KEY1: u32 = 0x4567_0123;
+pub(crate) const KEY2: u32 = 0xCDEF_89AB;
+/// Option bytes in system memory, each followed by its complement
+const OB_BASE: u32 = 0x1FFF_F800;
+const OB_RDP_LEVEL0: u8 = 0xAA;
+const OB_RDP_LEVEL2: u8 = 0xCC;
+/// CR: OPTWRE, option bytes write enable
+const CR_OPTWRE: u32 = 1 << 9;
+/// CR: OBL_LAUNCH, reload option bytes with a system reset
+const CR_OBL_LAUNCH: u32 = 1 << 13;
+
+/// Readout protection level
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RdpLevel {
+    /// No protection
+    Level0,
+    /// No flash access by the debugger or when booted from RAM or system
+    /// memory, going back to level 0 mass erases the flash
+    Level1,
+    /// Like level 1, debugging and option byte changes are disabled for good
+    Level2,
+}
+
+/// User option bits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UserBits {
+    /// Watchdog enabled by software (WDG_SW), by hardware if cleared
+    pub wdg_sw: bool,
+    /// No reset when entering Stop mode (nRST_STOP)
+    pub nrst_stop: bool,
+    /// No reset when entering Standby mode (nRST_STDBY)
+    pub nrst_stdby: bool,
+    /// Boot from system memory if BOOT0 is set (nBOOT1), SRAM if cleared
+    pub nboot1: bool,
+    /// Analog supply monitor enabled (VDDA_MONITOR)
+    pub vdda_monitor: bool,
+    /// SRAM parity check disabled (SRAM_PE)
+    pub sram_pe: bool,
+}
+
+impl UserBits {
+    fn from_obr(obr: u32) -> Self {
+        UserBits { wdg_sw: obr & (1 << 8) != 0,
+                   nrst_stop: obr & (1 << 9) != 0,
+                   nrst_stdby: obr & (1 << 10) != 0,
+                   nboot1: obr & (1 << 12) != 0,
+                   vdda_monitor: obr & (1 << 13) != 0,
+                   sram_pe: obr & (1 << 14) != 0 }
+    }
+
+    fn bits(&self) -> u8 {
+        // reserved bits 3 and 7 stay set
+        0x88 | self.wdg_sw as u8
+        | (self.nrst_stop as u8) << 1
+        | (self.nrst_stdby as u8) << 2
+        | (self.nboot1 as u8) << 4
+        | (self.vdda_monitor as u8) << 5
+        | (self.sram_pe as u8) << 6
+    }
+}
+
+/// Option bytes as loaded at the last reset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OptionBytes {
+    /// Readout protection
+    pub rdp: RdpLevel,
+    /// User option bits
+    pub user: UserBits,
+    /// User data byte 0
+    pub data0: u8,
+    /// User data byte 1
+    pub data1: u8,
+    /// Write protected page groups, a set bit protects a group
+    ///
+    /// Bit `n` covers pages `2n` and `2n + 1`, on devices with more than 64
+    /// pages bit 31 covers all pages from 62 on.
+    pub wrp: u32,
+}
+
+impl OptionBytes {
+    /// Returns whether the page `group` is write protected
+    pub fn is_protected(&self, group: u8) -> bool {
+        self.wrp & (1 << group) != 0
+    }
+
+    /// Write protects the page `group` or lifts the protection
+    pub fn protect(&mut self, group: u8, protect: bool) {
+        if protect {
+            self.wrp |= 1 << group;
+        } else {
+            self.wrp &= !(1 << group);
+        }
+    }
+}
+
+/// Option byte programming error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptionError {
+    /// RDP level 2 requested through
+    /// [`PROG::program_option_bytes`](struct.PROG.html#method.program_option_bytes)
+    RdpLevel2,
+    /// An option byte wasn't erased before programming (PGERR)
+    Programming,
+    /// The option bytes are write protected (WRPRTERR)
+    WriteProtection,
+}
+
+impl PROG {
+    pub(crate) fn optkeyr(&mut self) -> &flash::OPTKEYR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*FLASH::ptr()).optkeyr }
+    }
+
+    /// Reads the option bytes loaded at the last reset
+    pub fn option_bytes(&mut self) -> OptionBytes {
+        // NOTE(unsafe) atomic read with no side effects
+        let obr = unsafe { (*FLASH::ptr()).obr.read().bits() };
+        let wrpr = unsafe { (*FLASH::ptr()).wrpr.read().bits() };
+
+        let rdp = match (obr >> 1) & 0b11 {
+            0b00 => RdpLevel::Level0,
+            0b01 => RdpLevel::Level1,
+            _ => RdpLevel::Level2,
+        };
+
+        OptionBytes { rdp,
+                      user: UserBits::from_obr(obr),
+                      data0: (obr >> 16) as u8,
+                      data1: (obr >> 24) as u8,
+                      // WRPR is active low
+                      wrp: !wrpr }
+    }
+
+    /// Erases and programs the option bytes
+    ///
+    /// Refuses RDP level 2, see
+    /// [`program_rdp_level2`](#method.program_rdp_level2). The new values
+    /// are loaded by [`reload_option_bytes`](#method.reload_option_bytes) or
+    /// at the next power-on reset. Going from RDP level 1 to level 0 mass
+    /// erases the flash on reload.
+    pub fn program_option_bytes(&mut self,
+                                ob: &OptionBytes)
+                                -> Result<(), OptionError> {
+        if ob.rdp == RdpLevel::Level2 {
+            return Err(OptionError::RdpLevel2);
+        }
+        self.write_option_bytes(ob)
+    }
+
+    /// Erases and programs the option bytes, including RDP level 2
+    ///
+    /// # Safety
+    ///
+    /// RDP level 2 permanently disables the debug interface, booting from
+    /// RAM or system memory and any further option byte change.
+    pub unsafe fn program_rdp_level2(&mut self,
+                                     ob: &OptionBytes)
+                                     -> Result<(), OptionError> {
+        self.write_option_bytes(ob)
+    }
+
+    /// Loads the programmed option bytes, resetting the device
+    pub fn reload_option_bytes(&mut self) -> ! {
+        self.unlock_option_bytes();
+        self.cr()
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_OBL_LAUNCH) });
+        loop {
+            asm::nop();
+        }
+    }
+
+    fn unlock_option_bytes(&mut self) {
+        while self.sr().read().bsy().bit_is_set() {}
+        // a wrong key sequence locks the FPEC until reset
+        if self.cr().read().lock().bit_is_set() {
+            self.keyr().write(|w| unsafe { w.bits(KEY1) });
+            self.keyr().write(|w| unsafe { w.bits(KEY2) });
+        }
+        if self.cr().read().bits() & CR_OPTWRE == 0 {
+            self.optkeyr().write(|w| unsafe { w.bits(KEY1) });
+            self.optkeyr().write(|w| unsafe { w.bits(KEY2) });
+        }
+    }
+
+    /// Waits for the current operation and clears its status flags
+    fn wait_option_bytes(&mut self) -> Result<(), OptionError> {
+        while self.sr().read().bsy().bit_is_set() {}
+
+        let sr = self.sr().read();
+        let result = if sr.wrprterr().bit_is_set() {
+            Err(OptionError::WriteProtection)
+        } else if sr.pgerr().bit_is_set() {
+            Err(OptionError::Programming)
+        } else {
+            Ok(())
+        };
+        // status flags are cleared by writing 1
+        self.sr().write(|w| {
+                     w.eop()
+                      .set_bit()
+                      .wrprterr()
+                      .set_bit()
+                      .pgerr()
+                      .set_bit()
+                 });
+        result
+    }
+
+    fn write_option_bytes(&mut self,
+                          ob: &OptionBytes)
+                          -> Result<(), OptionError> {
+        let rdp = match ob.rdp {
+            RdpLevel::Level0 => OB_RDP_LEVEL0,
+            // anything but the level 0 and level 2 keys
+            RdpLevel::Level1 => 0xFF,
+            RdpLevel::Level2 => OB_RDP_LEVEL2,
+        };
+        // WRP bytes are active low
+        let wrp = !ob.wrp;
+        let bytes = [rdp,
+                     ob.user.bits(),
+                     ob.data0,
+                     ob.data1,
+                     wrp as u8,
+                     (wrp >> 8) as u8,
+                     (wrp >> 16) as u8,
+                     (wrp >> 24) as u8];
+
+        self.unlock_option_bytes();
+
+        self.cr().modify(|_, w| w.opter().set_bit());
+        self.cr().modify(|_, w| w.strt().set_bit());
+        let erased = self.wait_option_bytes();
+        self.cr().modify(|_, w| w.opter().clear_bit());
+
+        let result = erased.and_then(|_| {
+            self.cr().modify(|_, w| w.optpg().set_bit());
+            // the complement bytes are written by hardware
+            let result = bytes.iter().enumerate().try_for_each(|(i, byte)| {
+                let address = OB_BASE + 2 * i as u32;
+                // NOTE(unsafe) half-word write to option bytes with OPTPG set
+                unsafe {
+                    core::ptr::write_volatile(address as *mut u16,
+                                              u16::from(*byte));
+                }
+                self.wait_option_bytes()
+            });
+            self.cr().modify(|_, w| w.optpg().clear_bit());
+            result
+        });
+
+        self.cr()
+            .modify(|r, w| unsafe { w.bits(r.bits() & !CR_OPTWRE) });
+        self.cr().modify(|_, w| w.lock().set_bit());
+        result
+    }
 }
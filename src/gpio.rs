@@ -1,15 +1,22 @@
 //! General Purpose Input / Output
-
-// TODO the pins here currently correspond to the LQFP-100 package. There
-// should be Cargo features that let you select different microcontroller
-// packages
-
+//!
+//! The ports and pins follow the package selected by one of the Cargo
+//! features `lqfp48`, `lqfp64`, `lqfp100`, `wlcsp100` or `lqfp144`, the last
+//! one is only available for the STM32F303xD/E. Without a package feature the
+//! pins of the LQFP-100 package are provided.
+//!
+//! `wlcsp100` is an alias of `lqfp100`, it provides the same pins and doesn't
+//! hide the ones that aren't bonded on the WLCSP-100 package.
+
+use crate::pac;
 use crate::rcc::AHB;
 use bobbin_bits::*;
 use core::intrinsics::transmute;
 use core::marker::PhantomData;
+use core::ptr;
+use cortex_m::interrupt;
 #[allow(deprecated)]
-use hal::digital::{InputPin, OutputPin, StatefulOutputPin};
+use hal::digital::{toggleable, InputPin, OutputPin, StatefulOutputPin};
 
 /// Marker trait for any pin
 pub trait GPIOPin {
@@ -202,6 +209,201 @@ gen_af!([(AF0, B0000),
          (AF14, B1110),
          (AF15, B1111)]);
 
+/// Pin modes driven from the output data register
+pub trait OutputMode: PinMode {}
+impl<OT: OutputType, OS: OutputSpeed> OutputMode for Output<OT, OS> {}
+
+/// Pin whose port is known from its type
+pub trait PortPin: GPIOPin {
+    /// Pin mode
+    type Mode: PinMode;
+
+    /// Writes `bits` to the BSRR register of the port
+    fn write_bsrr(bits: u32);
+    /// Reads the IDR register of the port
+    fn read_idr() -> u32;
+    /// Runs the lock key sequence for the port pins in `mask`
+    fn lock_pins(mask: u16) -> bool;
+}
+
+/// Pins of one port read and written together
+///
+/// Bit `k` of the data is mapped to the `k`-th pin. The pins are partially
+/// erased ones of the same type, so they're checked to share a port at
+/// compile time, e.g. for an 8-bit bus on PD0-PD7:
+///
+/// ```ignore
+/// let bus = Parallel::new([pd0.downgrade(), pd1.downgrade(), ...]);
+/// ```
+pub struct Parallel<P, X> {
+    pins: P,
+    mask: u16,
+    _pin: PhantomData<X>,
+}
+
+impl<P, X> Parallel<P, X>
+    where P: AsRef<[X]>,
+          X: PortPin
+{
+    /// Groups `pins`, at most the 16 pins of a port
+    ///
+    /// # Panics
+    ///
+    /// Panics if a pin is given twice or there are more than 16 pins.
+    pub fn new(pins: P) -> Self {
+        assert!(pins.as_ref().len() <= 16, "more than 16 pins");
+        let mask = pins.as_ref().iter().fold(0u16, |mask, pin| {
+                                           let bit = 1 << pin.index();
+                                           assert!(mask & bit == 0,
+                                                   "duplicate pin");
+                                           mask | bit
+                                       });
+        Parallel { pins,
+                   mask,
+                   _pin: PhantomData }
+    }
+
+    /// Releases the pins
+    pub fn free(self) -> P {
+        self.pins
+    }
+
+    /// Locks the configuration of all pins until the next reset
+    ///
+    /// A port accepts a single lock key sequence per reset, so this locks
+    /// the pins together. Returns them unchanged if the sequence failed, e.g.
+    /// because pins of the port were locked before.
+    pub fn lock(self) -> Result<Locked<Self>, Self> {
+        if X::lock_pins(self.mask) {
+            Ok(Locked { pin: self })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Returns the port bits of the pins
+    pub fn mask(&self) -> u16 {
+        self.mask
+    }
+
+    /// Reads the levels of all pins with a single IDR access
+    pub fn read(&self) -> u16 {
+        let idr = X::read_idr();
+        self.pins
+            .as_ref()
+            .iter()
+            .enumerate()
+            .fold(0, |value, (k, pin)| {
+                value | (((idr >> pin.index()) & 1) as u16) << k
+            })
+    }
+}
+
+impl<P, X> Parallel<P, X>
+    where P: AsRef<[X]>,
+          X: PortPin,
+          X::Mode: OutputMode
+{
+    /// Drives all pins with a single BSRR access
+    pub fn write(&mut self, value: u16) {
+        let bits = self.pins
+                       .as_ref()
+                       .iter()
+                       .enumerate()
+                       .fold(0, |bits, (k, pin)| {
+                           if value & (1 << k) != 0 {
+                               bits | 1 << pin.index()
+                           } else {
+                               bits | 1 << (16 + pin.index())
+                           }
+                       });
+        X::write_bsrr(bits);
+    }
+}
+
+/// Pin with a locked configuration
+///
+/// The mode, pull, type, speed and alternate function of the pin can't be
+/// changed until the next reset, only its level.
+pub struct Locked<P> {
+    pin: P,
+}
+
+impl<P, X> Locked<Parallel<P, X>>
+    where P: AsRef<[X]>,
+          X: PortPin
+{
+    /// Returns the port bits of the pins
+    pub fn mask(&self) -> u16 {
+        self.pin.mask()
+    }
+
+    /// Reads the levels of all pins with a single IDR access
+    pub fn read(&self) -> u16 {
+        self.pin.read()
+    }
+}
+
+impl<P, X> Locked<Parallel<P, X>>
+    where P: AsRef<[X]>,
+          X: PortPin,
+          X::Mode: OutputMode
+{
+    /// Drives all pins with a single BSRR access
+    pub fn write(&mut self, value: u16) {
+        self.pin.write(value)
+    }
+}
+
+impl<P: GPIOPin> GPIOPin for Locked<P> {
+    fn group(&self) -> Group {
+        self.pin.group()
+    }
+
+    fn index(&self) -> u8 {
+        self.pin.index()
+    }
+}
+
+#[allow(deprecated)]
+impl<P: OutputPin> OutputPin for Locked<P> {
+    type Error = P::Error;
+
+    fn try_set_high(&mut self) -> Result<(), P::Error> {
+        self.pin.try_set_high()
+    }
+
+    fn try_set_low(&mut self) -> Result<(), P::Error> {
+        self.pin.try_set_low()
+    }
+}
+
+#[allow(deprecated)]
+impl<P: StatefulOutputPin> StatefulOutputPin for Locked<P> {
+    fn try_is_set_high(&self) -> Result<bool, P::Error> {
+        self.pin.try_is_set_high()
+    }
+
+    fn try_is_set_low(&self) -> Result<bool, P::Error> {
+        self.pin.try_is_set_low()
+    }
+}
+
+impl<P: StatefulOutputPin> toggleable::Default for Locked<P> {}
+
+#[allow(deprecated)]
+impl<P: InputPin> InputPin for Locked<P> {
+    type Error = P::Error;
+
+    fn try_is_high(&self) -> Result<bool, P::Error> {
+        self.pin.try_is_high()
+    }
+
+    fn try_is_low(&self) -> Result<bool, P::Error> {
+        self.pin.try_is_low()
+    }
+}
+
 /// Extension trait to split a GPIO peripheral in independent pins and registers
 pub trait GpioExt {
     /// The to split the GPIO into
@@ -213,13 +415,14 @@ pub trait GpioExt {
 
 macro_rules! gpio {
     ($GPIOX:ident, $Gpiox:ident, $gpiox:ident, $iopxenr:ident, $iopxrst:ident, $group: ident, $PXx:ident, [
-        $($PXi:ident: ($pxi:ident, $i:expr, $AFR:ident),)+
+        $($(#[$attr:meta])* $PXi:ident: ($pxi:ident, $i:expr, $AFR:ident),)+
     ]) => {
         use crate::pac::$GPIOX;
         /// GPIO ports
         pub struct $Gpiox {
             $(
                 /// Pin $PXi
+                $(#[$attr])*
                 pub $pxi: $PXi<PullNone, Input>,
             )+
         }
@@ -234,6 +437,7 @@ macro_rules! gpio {
 
                 $Gpiox {
                     $(
+                        $(#[$attr])*
                         $pxi: $PXi {
                             _pullup_state: PhantomData,
                             _pin_mode: PhantomData
@@ -273,10 +477,30 @@ macro_rules! gpio {
                 let otyper = unsafe { &(*$GPIOX::ptr()).otyper };
                 let type_bits:u32 = ot.output_type().into();
                 otyper.modify(|r, w| unsafe {
-                    w.bits(r.bits() | (type_bits << index))
+                    w.bits((r.bits() & !(1 << index)) | (type_bits << index))
                 });
             }
 
+            /// Locks the pins in `mask`, LCKR itself is frozen afterwards so
+            /// this only succeeds once per reset
+            fn lock(mask: u16) -> bool {
+                let lckr = unsafe { &(*$GPIOX::ptr()).lckr };
+                // LCKK: lock key
+                let lckk = 1 << 16;
+                let pins = u32::from(mask);
+                interrupt::free(|_| {
+                    // key sequence: 1, 0, 1 then a read
+                    lckr.write(|w| unsafe { w.bits(lckk | pins) });
+                    lckr.write(|w| unsafe { w.bits(pins) });
+                    lckr.write(|w| unsafe { w.bits(lckk | pins) });
+                    lckr.read();
+                    // a sequence on a locked port is ignored, LCKK alone
+                    // doesn't tell the pins were locked
+                    let bits = lckr.read().bits();
+                    bits & lckk != 0 && bits & pins == pins
+                })
+            }
+
         }
 
 
@@ -287,9 +511,51 @@ macro_rules! gpio {
             _pin_mode: PhantomData<PM>
         }
 
+        impl<PT: PullType, PM: PinMode> GPIOPin for $PXx<PT, PM> {
+            fn group(&self) -> Group {
+                Group::$group
+            }
+
+            fn index(&self) -> u8 {
+                self.i
+            }
+        }
+
+        impl<PT: PullType, PM: PinMode> $PXx<PT, PM> {
+            /// Erases the port from the type
+            pub fn downgrade(self) -> Pin<PT, PM> {
+                Pin {
+                    group: Group::$group,
+                    i: self.i,
+                    _pullup_state: PhantomData,
+                    _pin_mode: PhantomData
+                }
+            }
+        }
+
+        impl<PT: PullType, PM: PinMode> PortPin for $PXx<PT, PM> {
+            type Mode = PM;
+
+            fn write_bsrr(bits: u32) {
+                // NOTE(unsafe) atomic write to a stateless register
+                unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(bits)) }
+            }
+
+            fn read_idr() -> u32 {
+                // NOTE(unsafe) atomic read with no side effects
+                unsafe { (*$GPIOX::ptr()).idr.read().bits() }
+            }
+
+            fn lock_pins(mask: u16) -> bool {
+                $Gpiox::lock(mask)
+            }
+        }
+
         #[allow(deprecated)]
         impl<PT: PullType, OT: OutputType, OS: OutputSpeed>
             OutputPin for $PXx<PT, Output<OT, OS>> {
+                type Error = ();
+
                 fn try_set_high(&mut self) -> Result<(), Self::Error> {
                     // NOTE(unsafe) atomic write to a stateless register
                     Ok(unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(1 << self.i)) })
@@ -334,11 +600,13 @@ macro_rules! gpio {
 
         $(
             /// Pin
+            $(#[$attr])*
             pub struct $PXi<PT: PullType, PM: PinMode> {
                 _pullup_state: PhantomData<PT>,
                 _pin_mode: PhantomData<PM>
             }
 
+            $(#[$attr])*
             impl <PT: PullType, PM: PinMode> GPIOPin for $PXi<PT, PM> {
                 fn group(&self) -> Group {
                     Group::$group
@@ -349,6 +617,7 @@ macro_rules! gpio {
                 }
             }
 
+            $(#[$attr])*
             impl<PT: PullType, PM: PinMode> $PXi<PT, PM> {
                 /// Erases the pin number from the type
                 ///
@@ -405,6 +674,9 @@ macro_rules! gpio {
                 }
 
                 /// Set io_mode to altfn and set alternating function
+                ///
+                /// `af` isn't checked against the pin, the peripherals take
+                /// pins through traits like `serial::TxPin` instead.
                 pub fn alternating<AFN: AltFnNum>(self, af: AFN) -> $PXi<PT, AltFn<AFN, PushPull, LowSpeed>> {
                     let result: $PXi<PT, AltFn<AFN, PushPull, LowSpeed>> =
                         unsafe { transmute(self) };
@@ -416,8 +688,30 @@ macro_rules! gpio {
                     $Gpiox::set_pin_mode::<AltFn<AFN, PushPull, LowSpeed>>($i);
                     result2
                 }
+
+                /// Turns the pin into an input whose mode can be changed at
+                /// runtime
+                pub fn into_dynamic(self) -> DynamicPin<PT> {
+                    self.downgrade().downgrade().into_dynamic()
+                }
+
+                /// Locks the configuration of the pin until the next reset
+                ///
+                /// A port accepts a single lock key sequence per reset, use
+                /// [`Parallel::lock`](struct.Parallel.html#method.lock) to
+                /// lock several pins of the port. Returns the pin unchanged if
+                /// the sequence failed, e.g. because the port was locked
+                /// before.
+                pub fn lock(self) -> Result<Locked<Self>, Self> {
+                    if $Gpiox::lock(1 << $i) {
+                        Ok(Locked { pin: self })
+                    } else {
+                        Err(self)
+                    }
+                }
             }
 
+            $(#[$attr])*
             impl<PT: PullType, OT: OutputType, OS: OutputSpeed> $PXi<PT, Output<OT, OS>> {
                 /// Set output type
                 pub fn output_type<NOT: OutputType>(self, ot: NOT) -> $PXi<PT, Output<NOT, OS>> {
@@ -442,6 +736,7 @@ macro_rules! gpio {
                 }
             }
 
+            $(#[$attr])*
             impl<PT: PullType, AFN: AltFnNum, OT: OutputType, OS: OutputSpeed> $PXi<PT, AltFn<AFN, OT, OS>> {
                 /// Set output type
                 pub fn output_type<NOT: OutputType>(self, ot: NOT) -> $PXi<PT, AltFn<AFN, NOT, OS>> {
@@ -470,6 +765,7 @@ macro_rules! gpio {
                 }
             }
 
+            $(#[$attr])*
             #[allow(deprecated)]
             impl<PT: PullType, OT:OutputType, OS:OutputSpeed> OutputPin
                 for $PXi<PT, Output<OT, OS>> {
@@ -486,6 +782,7 @@ macro_rules! gpio {
                     }
                 }
 
+            $(#[$attr])*
             #[allow(deprecated)]
             impl<PT: PullType, AN: AltFnNum, OT:OutputType, OS:OutputSpeed> OutputPin
                 for $PXi<PT, AltFn<AN, OT, OS>> {
@@ -502,13 +799,12 @@ macro_rules! gpio {
                     }
                 }
 
+            $(#[$attr])*
             #[allow(deprecated)]
             impl<PT: PullType, OT:OutputType, OS:OutputSpeed> StatefulOutputPin
                 for $PXi<PT, Output<OT, OS>> {
-                    type Error = ();
-
                     fn try_is_set_high(&self) -> Result<bool, Self::Error> {
-                        Ok(!self.is_set_low())
+                        Ok(!self.try_is_set_low()?)
                     }
 
                     fn try_is_set_low(&self) -> Result<bool, Self::Error> {
@@ -517,9 +813,11 @@ macro_rules! gpio {
                     }
                 }
 
-            /*impl<PT: PullType, OT:OutputType, OS:OutputSpeed> ToggleableOutputPin
-                for $PXi<PT, Output<OT, OS>> {}*/
+            $(#[$attr])*
+            impl<PT: PullType, OT:OutputType, OS:OutputSpeed> toggleable::Default
+                for $PXi<PT, Output<OT, OS>> {}
 
+            $(#[$attr])*
             #[allow(deprecated)]
             impl<PT: PullType> InputPin for $PXi<PT, Input> {
                 type Error = ();
@@ -539,7 +837,8 @@ macro_rules! gpio {
     }
 }
 
-/// GPIO pin group [A-F]
+/// GPIO pin group [A-H]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Group {
     /// GPIOA
     A,
@@ -555,6 +854,273 @@ pub enum Group {
     F,
     /// GPIOG
     G,
+    /// GPIOH
+    H,
+}
+
+impl Group {
+    /// Address of the register block
+    fn address(self) -> usize {
+        match self {
+            Group::A => pac::GPIOA::ptr() as usize,
+            Group::B => pac::GPIOB::ptr() as usize,
+            Group::C => pac::GPIOC::ptr() as usize,
+            Group::D => pac::GPIOD::ptr() as usize,
+            Group::E => pac::GPIOE::ptr() as usize,
+            Group::F => pac::GPIOF::ptr() as usize,
+            #[cfg(feature = "lqfp144")]
+            Group::G => pac::GPIOG::ptr() as usize,
+            #[cfg(feature = "lqfp144")]
+            Group::H => pac::GPIOH::ptr() as usize,
+            // no pins are handed out for missing ports
+            #[cfg(not(feature = "lqfp144"))]
+            Group::G | Group::H => unreachable!(),
+        }
+    }
+
+    fn read(self, offset: usize) -> u32 {
+        // NOTE(unsafe) atomic read of a GPIO register
+        unsafe { ptr::read_volatile((self.address() + offset) as *const u32) }
+    }
+
+    fn write(self, offset: usize, bits: u32) {
+        // NOTE(unsafe) atomic write of a GPIO register
+        unsafe {
+            ptr::write_volatile((self.address() + offset) as *mut u32, bits)
+        }
+    }
+
+    fn modify(self, offset: usize, mask: u32, bits: u32) {
+        let r = self.read(offset);
+        self.write(offset, (r & !mask) | bits);
+    }
+}
+
+// register offsets
+const MODER: usize = 0x00;
+const OTYPER: usize = 0x04;
+const IDR: usize = 0x10;
+const ODR: usize = 0x14;
+const BSRR: usize = 0x18;
+
+/// Fully erased pin
+///
+/// Pins of all ports have the same type, e.g. to collect them in an array.
+pub struct Pin<PT: PullType, PM: PinMode> {
+    group: Group,
+    i: u8,
+    _pullup_state: PhantomData<PT>,
+    _pin_mode: PhantomData<PM>,
+}
+
+impl<PT: PullType, PM: PinMode> GPIOPin for Pin<PT, PM> {
+    fn group(&self) -> Group {
+        self.group
+    }
+
+    fn index(&self) -> u8 {
+        self.i
+    }
+}
+
+impl<PT: PullType, PM: PinMode> Pin<PT, PM> {
+    /// Turns the pin into an input whose mode can be changed at runtime
+    pub fn into_dynamic(self) -> DynamicPin<PT> {
+        let mut pin = DynamicPin { group: self.group,
+                                   i: self.i,
+                                   mode: DynamicMode::Input,
+                                   _pullup_state: PhantomData };
+        pin.make_input();
+        pin
+    }
+}
+
+#[allow(deprecated)]
+impl<PT: PullType, OT: OutputType, OS: OutputSpeed> OutputPin
+    for Pin<PT, Output<OT, OS>>
+{
+    type Error = ();
+
+    fn try_set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(self.group.write(BSRR, 1 << self.i))
+    }
+
+    fn try_set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(self.group.write(BSRR, 1 << (16 + self.i)))
+    }
+}
+
+#[allow(deprecated)]
+impl<PT: PullType, AN: AltFnNum, OT: OutputType, OS: OutputSpeed> OutputPin
+    for Pin<PT, AltFn<AN, OT, OS>>
+{
+    type Error = ();
+
+    fn try_set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(self.group.write(BSRR, 1 << self.i))
+    }
+
+    fn try_set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(self.group.write(BSRR, 1 << (16 + self.i)))
+    }
+}
+
+#[allow(deprecated)]
+impl<PT: PullType, OT: OutputType, OS: OutputSpeed> StatefulOutputPin
+    for Pin<PT, Output<OT, OS>>
+{
+    fn try_is_set_high(&self) -> Result<bool, Self::Error> {
+        Ok(!self.try_is_set_low()?)
+    }
+
+    fn try_is_set_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.group.read(ODR) & (1 << self.i) == 0)
+    }
+}
+
+impl<PT: PullType, OT: OutputType, OS: OutputSpeed> toggleable::Default
+    for Pin<PT, Output<OT, OS>>
+{
+}
+
+#[allow(deprecated)]
+impl<PT: PullType> InputPin for Pin<PT, Input> {
+    type Error = ();
+
+    fn try_is_high(&self) -> Result<bool, Self::Error> {
+        Ok(!self.try_is_low()?)
+    }
+
+    fn try_is_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.group.read(IDR) & (1 << self.i) == 0)
+    }
+}
+
+/// Mode of a [`DynamicPin`](struct.DynamicPin.html)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DynamicMode {
+    /// Input
+    Input,
+    /// Push pull output
+    PushPull,
+    /// Open drain output, the level can still be read
+    OpenDrain,
+}
+
+/// Error of a [`DynamicPin`](struct.DynamicPin.html) used in the wrong mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PinModeError {
+    /// The pin is an input
+    IncorrectMode,
+}
+
+/// Fully erased pin switching between input and output at runtime, e.g. for
+/// bit-banging bidirectional buses
+pub struct DynamicPin<PT: PullType> {
+    group: Group,
+    i: u8,
+    mode: DynamicMode,
+    _pullup_state: PhantomData<PT>,
+}
+
+impl<PT: PullType> DynamicPin<PT> {
+    /// Returns the current mode
+    pub fn mode(&self) -> DynamicMode {
+        self.mode
+    }
+
+    /// Switches to input
+    pub fn make_input(&mut self) {
+        self.set_mode(DynamicMode::Input);
+    }
+
+    /// Switches to push pull output
+    pub fn make_push_pull_output(&mut self) {
+        self.set_mode(DynamicMode::PushPull);
+    }
+
+    /// Switches to open drain output
+    pub fn make_open_drain_output(&mut self) {
+        self.set_mode(DynamicMode::OpenDrain);
+    }
+
+    /// Switches to `mode`
+    pub fn set_mode(&mut self, mode: DynamicMode) {
+        let mode_bits: u32 = match mode {
+            DynamicMode::Input => Input::pin_mode().into(),
+            DynamicMode::PushPull | DynamicMode::OpenDrain => {
+                let ot = if mode == DynamicMode::OpenDrain {
+                    OpenDrain.output_type()
+                } else {
+                    PushPull.output_type()
+                };
+                let type_bits: u32 = ot.into();
+                self.group.modify(OTYPER, 1 << self.i, type_bits << self.i);
+                Output::<PushPull, LowSpeed>::pin_mode().into()
+            }
+        };
+        let offset = 2 * self.i;
+        self.group.modify(MODER, 0b11 << offset, mode_bits << offset);
+        self.mode = mode;
+    }
+}
+
+impl<PT: PullType> GPIOPin for DynamicPin<PT> {
+    fn group(&self) -> Group {
+        self.group
+    }
+
+    fn index(&self) -> u8 {
+        self.i
+    }
+}
+
+#[allow(deprecated)]
+impl<PT: PullType> OutputPin for DynamicPin<PT> {
+    type Error = PinModeError;
+
+    fn try_set_high(&mut self) -> Result<(), Self::Error> {
+        match self.mode {
+            DynamicMode::Input => Err(PinModeError::IncorrectMode),
+            _ => Ok(self.group.write(BSRR, 1 << self.i)),
+        }
+    }
+
+    fn try_set_low(&mut self) -> Result<(), Self::Error> {
+        match self.mode {
+            DynamicMode::Input => Err(PinModeError::IncorrectMode),
+            _ => Ok(self.group.write(BSRR, 1 << (16 + self.i))),
+        }
+    }
+}
+
+#[allow(deprecated)]
+impl<PT: PullType> StatefulOutputPin for DynamicPin<PT> {
+    fn try_is_set_high(&self) -> Result<bool, Self::Error> {
+        Ok(!self.try_is_set_low()?)
+    }
+
+    fn try_is_set_low(&self) -> Result<bool, Self::Error> {
+        match self.mode {
+            DynamicMode::Input => Err(PinModeError::IncorrectMode),
+            _ => Ok(self.group.read(ODR) & (1 << self.i) == 0),
+        }
+    }
+}
+
+impl<PT: PullType> toggleable::Default for DynamicPin<PT> {}
+
+#[allow(deprecated)]
+impl<PT: PullType> InputPin for DynamicPin<PT> {
+    type Error = PinModeError;
+
+    fn try_is_high(&self) -> Result<bool, Self::Error> {
+        Ok(!self.try_is_low()?)
+    }
+
+    fn try_is_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.group.read(IDR) & (1 << self.i) == 0)
+    }
 }
 
 gpio!(GPIOA, Gpioa, gpioa, iopaen, ioparst, A, PAx, [
@@ -596,43 +1162,73 @@ gpio!(GPIOB, Gpiob, gpiob, iopben, iopbrst, B, PBx, [
 ]);
 
 gpio!(GPIOC, Gpioc, gpioc, iopcen, iopcrst, C, PCx, [
+    #[cfg(not(feature = "lqfp48"))]
     PC0: (pc0, 0, afrl),
+    #[cfg(not(feature = "lqfp48"))]
     PC1: (pc1, 1, afrl),
+    #[cfg(not(feature = "lqfp48"))]
     PC2: (pc2, 2, afrl),
+    #[cfg(not(feature = "lqfp48"))]
     PC3: (pc3, 3, afrl),
+    #[cfg(not(feature = "lqfp48"))]
     PC4: (pc4, 4, afrl),
+    #[cfg(not(feature = "lqfp48"))]
     PC5: (pc5, 5, afrl),
+    #[cfg(not(feature = "lqfp48"))]
     PC6: (pc6, 6, afrl),
+    #[cfg(not(feature = "lqfp48"))]
     PC7: (pc7, 7, afrl),
+    #[cfg(not(feature = "lqfp48"))]
     PC8: (pc8, 8, afrh),
+    #[cfg(not(feature = "lqfp48"))]
     PC9: (pc9, 9, afrh),
+    #[cfg(not(feature = "lqfp48"))]
     PC10: (pc10, 10, afrh),
+    #[cfg(not(feature = "lqfp48"))]
     PC11: (pc11, 11, afrh),
+    #[cfg(not(feature = "lqfp48"))]
     PC12: (pc12, 12, afrh),
     PC13: (pc13, 13, afrh),
     PC14: (pc14, 14, afrh),
     PC15: (pc15, 15, afrh),
 ]);
 
+#[cfg(not(feature = "lqfp48"))]
 gpio!(GPIOD, Gpiod, gpiod, iopden, iopdrst, D, PDx, [
+    #[cfg(not(feature = "lqfp64"))]
     PD0: (pd0, 0, afrl),
+    #[cfg(not(feature = "lqfp64"))]
     PD1: (pd1, 1, afrl),
     PD2: (pd2, 2, afrl),
+    #[cfg(not(feature = "lqfp64"))]
     PD3: (pd3, 3, afrl),
+    #[cfg(not(feature = "lqfp64"))]
     PD4: (pd4, 4, afrl),
+    #[cfg(not(feature = "lqfp64"))]
     PD5: (pd5, 5, afrl),
+    #[cfg(not(feature = "lqfp64"))]
     PD6: (pd6, 6, afrl),
+    #[cfg(not(feature = "lqfp64"))]
     PD7: (pd7, 7, afrl),
+    #[cfg(not(feature = "lqfp64"))]
     PD8: (pd8, 8, afrh),
+    #[cfg(not(feature = "lqfp64"))]
     PD9: (pd9, 9, afrh),
+    #[cfg(not(feature = "lqfp64"))]
     PD10: (pd10, 10, afrh),
+    #[cfg(not(feature = "lqfp64"))]
     PD11: (pd11, 11, afrh),
+    #[cfg(not(feature = "lqfp64"))]
     PD12: (pd12, 12, afrh),
+    #[cfg(not(feature = "lqfp64"))]
     PD13: (pd13, 13, afrh),
+    #[cfg(not(feature = "lqfp64"))]
     PD14: (pd14, 14, afrh),
+    #[cfg(not(feature = "lqfp64"))]
     PD15: (pd15, 15, afrh),
 ]);
 
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
 gpio!(GPIOE, Gpioe, gpioe, iopeen, ioperst, E, PEx, [
     PE0: (pe0, 0, afrl),
     PE1: (pe1, 1, afrl),
@@ -655,9 +1251,59 @@ gpio!(GPIOE, Gpioe, gpioe, iopeen, ioperst, E, PEx, [
 gpio!(GPIOF, Gpiof, gpiof, iopfen, iopfrst, F, PFx, [
     PF0: (pf0, 0, afrl),
     PF1: (pf1, 1, afrl),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
     PF2: (pf2, 2, afrl),
-    PF4: (pf3, 4, afrl),
+    #[cfg(feature = "lqfp144")]
+    PF3: (pf3, 3, afrl),
+    #[cfg(not(feature = "lqfp48"))]
+    PF4: (pf4, 4, afrl),
+    #[cfg(feature = "lqfp144")]
+    PF5: (pf5, 5, afrl),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
     PF6: (pf6, 6, afrl),
+    #[cfg(feature = "lqfp144")]
+    PF7: (pf7, 7, afrl),
+    #[cfg(feature = "lqfp144")]
+    PF8: (pf8, 8, afrh),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
     PF9: (pf9, 9, afrh),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
     PF10: (pf10, 10, afrh),
+    #[cfg(feature = "lqfp144")]
+    PF11: (pf11, 11, afrh),
+    #[cfg(feature = "lqfp144")]
+    PF12: (pf12, 12, afrh),
+    #[cfg(feature = "lqfp144")]
+    PF13: (pf13, 13, afrh),
+    #[cfg(feature = "lqfp144")]
+    PF14: (pf14, 14, afrh),
+    #[cfg(feature = "lqfp144")]
+    PF15: (pf15, 15, afrh),
+]);
+
+#[cfg(feature = "lqfp144")]
+gpio!(GPIOG, Gpiog, gpiog, iopgen, iopgrst, G, PGx, [
+    PG0: (pg0, 0, afrl),
+    PG1: (pg1, 1, afrl),
+    PG2: (pg2, 2, afrl),
+    PG3: (pg3, 3, afrl),
+    PG4: (pg4, 4, afrl),
+    PG5: (pg5, 5, afrl),
+    PG6: (pg6, 6, afrl),
+    PG7: (pg7, 7, afrl),
+    PG8: (pg8, 8, afrh),
+    PG9: (pg9, 9, afrh),
+    PG10: (pg10, 10, afrh),
+    PG11: (pg11, 11, afrh),
+    PG12: (pg12, 12, afrh),
+    PG13: (pg13, 13, afrh),
+    PG14: (pg14, 14, afrh),
+    PG15: (pg15, 15, afrh),
+]);
+
+#[cfg(feature = "lqfp144")]
+gpio!(GPIOH, Gpioh, gpioh, iophen, iophrst, H, PHx, [
+    PH0: (ph0, 0, afrl),
+    PH1: (ph1, 1, afrl),
+    PH2: (ph2, 2, afrl),
 ]);
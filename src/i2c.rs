@@ -7,8 +7,10 @@ use crate::gpio::{AltFn, PinMode, PullType};
 use crate::gpio::{HighSpeed, PushPull, AF4};
 use crate::gpio::{PA10, PA14, PA15, PA9};
 use crate::gpio::{PB6, PB7, PB8, PB9};
-use crate::gpio::{PF0, PF1, PF6};
-use crate::rcc::Clocks;
+use crate::gpio::{PF0, PF1};
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+use crate::gpio::PF6;
+use crate::rcc::{Clocks, Reclock};
 use crate::time::Hertz;
 use hal::blocking::i2c::{Read, Write, WriteRead};
 
@@ -33,6 +35,7 @@ pub enum Error {
 pub struct I2c<I2C, PINS> {
     i2c: I2C,
     pins: PINS,
+    freq: u32,
 }
 
 /// I2c extension for I2C
@@ -50,6 +53,64 @@ pub trait I2cExt<I2C, ISCL, ISDA, SCL, SDA> {
         where F: Into<Hertz<u32>>;
 }
 
+/// Error reported when the I2C peripheral can't follow a clock change
+#[derive(Debug)]
+pub enum ReclockError {
+    /// The bus frequency can't be derived from the new kernel clock
+    Frequency,
+}
+
+/// TIMINGR fields (PRESC, SCLL, SCLH, SDADEL, SCLDEL) for `freq`, `None` if
+/// they don't fit
+fn timing(i2cclk: u32, freq: u32) -> Option<(u8, u8, u8, u8, u8)> {
+    // TODO review compliance with the timing requirements of I2C
+    // t_I2CCLK = 1 / I2CCLK
+    // t_PRESC  = (PRESC + 1) * t_I2CCLK
+    // t_SCLL   = (SCLL + 1) * t_PRESC
+    // t_SCLH   = (SCLH + 1) * t_PRESC
+    //
+    // t_SYNC1 + t_SYNC2 > 4 * t_I2CCLK
+    // t_SCL ~= t_SYNC1 + t_SYNC2 + t_SCLL + t_SCLH
+    let ratio = (i2cclk / freq).checked_sub(4)?;
+    let (presc, scll, sclh, sdadel, scldel) = if freq >= 100_000 {
+        // fast-mode or fast-mode plus
+        // here we pick SCLL + 1 = 2 * (SCLH + 1)
+        let presc = ratio / 387;
+        let sclh = (ratio / (presc + 1)).checked_sub(3)? / 3;
+        let scll = 2 * (sclh + 1) - 1;
+        let (sdadel, scldel) = if freq > 400_000 {
+            // fast-mode plus
+            let sdadel = 0;
+            let scldel = (i2cclk / 4_000_000 / (presc + 1)).checked_sub(1)?;
+            (sdadel, scldel)
+        } else {
+            // fast-mode
+            let sdadel = i2cclk / 8_000_000 / (presc + 1);
+            let scldel = (i2cclk / 2_000_000 / (presc + 1)).checked_sub(1)?;
+            (sdadel, scldel)
+        };
+        (presc, scll, sclh, sdadel, scldel)
+    } else {
+        // standard-mode
+        // here we pick SCLL = SCLH
+        let presc = ratio / 514;
+        let sclh = (ratio / (presc + 1)).checked_sub(2)? / 2;
+        let scll = sclh;
+        let sdadel = i2cclk / 2_000_000 / (presc + 1);
+        let scldel = (i2cclk / 800_000 / (presc + 1)).checked_sub(1)?;
+        (presc, scll, sclh, sdadel, scldel)
+    };
+    // 4-bit fields
+    if presc >= 16 || scldel >= 16 || sdadel >= 16 {
+        return None;
+    }
+    Some((u8(presc).ok()?,
+          u8(scll).ok()?,
+          u8(sclh).ok()?,
+          u8(sdadel).ok()?,
+          u8(scldel).ok()?))
+}
+
 macro_rules! busy_wait {
     ($i2c:expr, $flag:ident) => {
         loop {
@@ -70,136 +131,96 @@ macro_rules! busy_wait {
     };
 }
 
+/// SCL pin of `I2C`
+pub trait SclPin<I2C> {
+    /// Pin switched to the alternate function
+    type Output;
+    /// Switches the pin to the alternate function of `I2C`
+    fn setup(self) -> Self::Output;
+}
+
+/// SDA pin of `I2C`
+pub trait SdaPin<I2C> {
+    /// Pin switched to the alternate function
+    type Output;
+    /// Switches the pin to the alternate function of `I2C`
+    fn setup(self) -> Self::Output;
+}
+
+macro_rules! pins {
+    ($($(#[$attr:meta])*
+       $PIN:ident: ($Pin:ident, $I2CX:ident, $afn:ident),)+) => {
+        $(
+            $(#[$attr])*
+            impl<PT: PullType, PM: PinMode> $Pin<$I2CX> for $PIN<PT, PM> {
+                type Output = $PIN<PT, AltFn<$afn, PushPull, HighSpeed>>;
+
+                fn setup(self) -> Self::Output {
+                    self.alternating($afn).output_speed(HighSpeed)
+                }
+            }
+        )+
+    };
+}
+
 macro_rules! i2c {
     ($I2CX:ident,
      $i2cXen:ident,
      $i2cXrst:ident,
-     $afn:ident,
-     $speed:ident,
-     scl: [$($scl: ident, )+],
-     sda: $sda: tt
+     $i2cXclk:ident
     ) => {
-        i2c!{
-            $I2CX,
-            $i2cXen,
-            $i2cXrst,
-            $afn,
-            $speed,
-            [$(
-                ($scl, $sda),
-            )+]
+        impl<ISCL, ISDA> I2cExt<$I2CX, ISCL, ISDA, ISCL::Output, ISDA::Output>
+            for $I2CX
+            where ISCL: SclPin<$I2CX>,
+                  ISDA: SdaPin<$I2CX>
+        {
+            fn i2c<F>(
+                self,
+                pins: (ISCL, ISDA),
+                freq: F,
+                clocks: Clocks)
+                -> I2c<$I2CX, (ISCL::Output, ISDA::Output)> where
+                F: Into<Hertz<u32>>,
+            {
+                let outpins = (pins.0.setup(), pins.1.setup());
+                let apbenr = unsafe { &(*RCC::ptr()).apb1enr };
+                let apbrstr = unsafe { &(*RCC::ptr()).apb1rstr };
+
+                apbenr.modify(|_, w| w.$i2cXen().enabled());
+                apbrstr.modify(|_, w| w.$i2cXrst().set_bit());
+                apbrstr.modify(|_, w| w.$i2cXrst().clear_bit());
+
+                let freq = freq.into().0;
+
+                // TODO: remove assert, return error?
+                assert!(freq <= 1_000_000);
+
+                let (presc, scll, sclh, sdadel, scldel) =
+                    timing(clocks.$i2cXclk().0, freq)
+                        .expect("I2C frequency out of range");
+
+                // Configure for "fast mode" (400 KHz)
+                self.timingr.write(|w|
+                    w.presc()
+                        .bits(presc)
+                        .scll()
+                        .bits(scll)
+                        .sclh()
+                        .bits(sclh)
+                        .sdadel()
+                        .bits(sdadel)
+                        .scldel()
+                        .bits(scldel)
+                );
+
+                // Enable the peripheral
+                self.cr1.write(|w| w.pe().set_bit());
+
+                I2c { i2c: self,
+                      pins: outpins,
+                      freq }
+            }
         }
-    };
-    ($I2CX:ident,
-     $i2cXen:ident,
-     $i2cXrst:ident,
-     $afn:ident,
-     $speed:ident,
-     [$(($scl: ident, [$($sda:ident, )+]),)+]
-    ) => {
-        $(
-            $(
-                impl<PT: PullType, PM: PinMode>
-                    I2cExt<$I2CX,
-                $scl<PT, PM>,
-                $sda<PT, PM>,
-                $scl<PT, AltFn<$afn, PushPull, $speed>>,
-                $sda<PT, AltFn<$afn, PushPull, $speed>>> for $I2CX
-                {
-                    fn i2c<F>(
-                        self,
-                        pins: ($scl<PT, PM>, $sda<PT, PM>),
-                        freq: F,
-                        clocks: Clocks)
-                        -> I2c<$I2CX,
-                    ($scl<PT, AltFn<$afn, PushPull, $speed>>,
-                     $sda<PT, AltFn<$afn, PushPull, $speed>>)> where
-                        F: Into<Hertz<u32>>,
-                    {
-                        let outpins = (pins.0.alternating($afn).output_speed($speed),
-                                       pins.1.alternating($afn).output_speed($speed));
-                        let apbenr = unsafe { &(*RCC::ptr()).apb1enr };
-                        let apbrstr = unsafe { &(*RCC::ptr()).apb1rstr };
-
-                        apbenr.modify(|_, w| w.$i2cXen().enabled());
-                        apbrstr.modify(|_, w| w.$i2cXrst().set_bit());
-                        apbrstr.modify(|_, w| w.$i2cXrst().clear_bit());
-
-                        let freq = freq.into().0;
-
-                        // TODO: remove assert, return error?
-                        assert!(freq <= 1_000_000);
-
-                        // TODO review compliance with the timing requirements of I2C
-                        // t_I2CCLK = 1 / PCLK1
-                        // t_PRESC  = (PRESC + 1) * t_I2CCLK
-                        // t_SCLL   = (SCLL + 1) * t_PRESC
-                        // t_SCLH   = (SCLH + 1) * t_PRESC
-                        //
-                        // t_SYNC1 + t_SYNC2 > 4 * t_I2CCLK
-                        // t_SCL ~= t_SYNC1 + t_SYNC2 + t_SCLL + t_SCLH
-                        let i2cclk = clocks.pclk1().0;
-                        let ratio = i2cclk / freq - 4;
-                        let (presc, scll, sclh, sdadel, scldel) = if freq >= 100_000 {
-                            // fast-mode or fast-mode plus
-                            // here we pick SCLL + 1 = 2 * (SCLH + 1)
-                            let presc = ratio / 387;
-                            let sclh = ((ratio / (presc + 1)) - 3) / 3;
-                            let scll = 2 * (sclh + 1) - 1;
-                            let (sdadel, scldel) = if freq > 400_000 {
-                                // fast-mode plus
-                                let sdadel = 0;
-                                let scldel = i2cclk / 4_000_000 / (presc + 1) - 1;
-                                (sdadel, scldel)
-                            } else {
-                                // fast-mode
-                                let sdadel = i2cclk / 8_000_000 / (presc + 1);
-                                let scldel = i2cclk / 2_000_000 / (presc + 1) - 1;
-                                (sdadel, scldel)
-                            };
-                            (presc, scll, sclh, sdadel, scldel)
-                        } else {
-                            // standard-mode
-                            // here we pick SCLL = SCLH
-                            let presc = ratio / 514;
-                            let sclh = ((ratio / (presc + 1)) - 2) / 2;
-                            let scll = sclh;
-                            let sdadel = i2cclk / 2_000_000 / (presc + 1);
-                            let scldel = i2cclk / 800_000 / (presc + 1) - 1;
-                            (presc, scll, sclh, sdadel, scldel)
-                        };
-                        // TODO: remove asserts
-                        let presc = u8(presc).unwrap();
-                        assert!(presc < 16);
-                        let scldel = u8(scldel).unwrap();
-                        assert!(scldel < 16);
-                        let sdadel = u8(sdadel).unwrap();
-                        assert!(sdadel < 16);
-                        let sclh = u8(sclh).unwrap();
-                        let scll = u8(scll).unwrap();
-
-                        // Configure for "fast mode" (400 KHz)
-                        self.timingr.write(|w|
-                            w.presc()
-                                .bits(presc)
-                                .scll()
-                                .bits(scll)
-                                .sclh()
-                                .bits(sclh)
-                                .sdadel()
-                                .bits(sdadel)
-                                .scldel()
-                                .bits(scldel)
-                        );
-
-                        // Enable the peripheral
-                        self.cr1.write(|w| w.pe().set_bit());
-
-                        I2c { i2c: self, pins: outpins }
-                    }
-                }
-            )+
-        )+
 
         impl<SCL, SDA> I2c<$I2CX, (SCL, SDA)> {
             /// Releases the I2C peripheral and associated pins
@@ -208,6 +229,33 @@ macro_rules! i2c {
             }
         }
 
+        impl<SCL, SDA> Reclock for I2c<$I2CX, (SCL, SDA)> {
+            type Error = ReclockError;
+
+            fn reclock(&mut self, clocks: Clocks) -> Result<(), Self::Error> {
+                let (presc, scll, sclh, sdadel, scldel) =
+                    timing(clocks.$i2cXclk().0, self.freq)
+                        .ok_or(ReclockError::Frequency)?;
+
+                // TIMINGR is only writable while PE=0
+                self.i2c.cr1.modify(|_, w| w.pe().clear_bit());
+                self.i2c.timingr.write(|w|
+                    w.presc()
+                        .bits(presc)
+                        .scll()
+                        .bits(scll)
+                        .sclh()
+                        .bits(sclh)
+                        .sdadel()
+                        .bits(sdadel)
+                        .scldel()
+                        .bits(scldel)
+                );
+                self.i2c.cr1.modify(|_, w| w.pe().set_bit());
+                Ok(())
+            }
+        }
+
         impl<PINS> Write for I2c<$I2CX, PINS> {
             type Error = Error;
 
@@ -360,12 +408,24 @@ macro_rules! i2c {
     }
 }
 
-i2c!(I2C1, i2c1en, i2c1rst, AF4, HighSpeed,
-     scl: [PB6, PB8, PA15, ],
-     sda: [PB7, PB9, PA14, ]);
-i2c!(I2C2, i2c2en, i2c2rst, AF4, HighSpeed,
-     scl: [PA9, PF1, PF6, ],
-     sda: [PA10, PF0, ]);
+// alternate functions from the datasheet
+pins! {
+    PA15: (SclPin, I2C1, AF4),
+    PB6: (SclPin, I2C1, AF4),
+    PB8: (SclPin, I2C1, AF4),
+    PA14: (SdaPin, I2C1, AF4),
+    PB7: (SdaPin, I2C1, AF4),
+    PB9: (SdaPin, I2C1, AF4),
+    PA9: (SclPin, I2C2, AF4),
+    PF1: (SclPin, I2C2, AF4),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+    PF6: (SclPin, I2C2, AF4),
+    PA10: (SdaPin, I2C2, AF4),
+    PF0: (SdaPin, I2C2, AF4),
+}
+
+i2c!(I2C1, i2c1en, i2c1rst, i2c1clk);
+i2c!(I2C2, i2c2en, i2c2rst, i2c2clk);
 
 // hal! {
 //     I2C1: (i2c1, i2c1en, i2c1rst),
@@ -47,6 +47,24 @@ pub use stm32f3::stm32f3x4 as pac;
 #[cfg(feature = "stm32f328")]
 pub use stm32f3::stm32f3x8 as pac;
 
+#[cfg(any(all(feature = "lqfp48",
+                any(feature = "lqfp64",
+                    feature = "lqfp100",
+                    feature = "wlcsp100",
+                    feature = "lqfp144")),
+          all(feature = "lqfp64",
+              any(feature = "lqfp100",
+                  feature = "wlcsp100",
+                  feature = "lqfp144")),
+          all(feature = "lqfp100",
+              any(feature = "wlcsp100", feature = "lqfp144")),
+          all(feature = "wlcsp100", feature = "lqfp144")))]
+compile_error!("Only one package feature can be selected");
+
+#[cfg(all(feature = "lqfp144",
+          not(any(feature = "stm32f303xd", feature = "stm32f303xe"))))]
+compile_error!("The LQFP-144 package is only available for STM32F303xD/E");
+
 #[cfg(feature = "device-selected")]
 pub use crate::pac as device;
 
@@ -55,33 +73,44 @@ pub use crate::pac as stm32;
 
 #[cfg(feature = "device-selected")]
 pub mod bb;
-//#[cfg(feature = "device-selected")]
-//pub mod delay;
+#[cfg(feature = "device-selected")]
+pub mod crashlog;
+#[cfg(feature = "device-selected")]
+pub mod crc;
+#[cfg(feature = "device-selected")]
+pub mod delay;
 #[cfg(feature = "device-selected")]
 pub mod dma;
-//#[cfg(feature = "device-selected")]
-//pub mod exti;
+pub mod eeprom;
+#[cfg(feature = "device-selected")]
+pub mod exti;
 #[cfg(feature = "device-selected")]
 pub mod flash;
-//#[cfg(feature = "device-selected")]
-//pub mod gpio;
-//#[cfg(feature = "device-selected")]
-//pub mod i2c;
-//#[cfg(feature = "device-selected")]
-//pub mod prelude;
-//#[cfg(feature = "device-selected")]
-//pub mod pwm;
+#[cfg(feature = "device-selected")]
+pub mod gpio;
+#[cfg(feature = "device-selected")]
+pub mod i2c;
+#[cfg(test)]
+mod mock_flash;
+#[cfg(feature = "device-selected")]
+pub mod prelude;
+#[cfg(feature = "device-selected")]
+pub mod pwm;
 #[cfg(feature = "device-selected")]
 pub mod rcc;
-//#[cfg(feature = "device-selected")]
-//pub mod serial;
-//#[cfg(feature = "device-selected")]
-//pub mod spi;
+#[cfg(feature = "device-selected")]
+pub mod serial;
+#[cfg(feature = "device-selected")]
+pub mod signature;
+#[cfg(feature = "device-selected")]
+pub mod spi;
 #[cfg(feature = "device-selected")]
 pub mod storage;
 #[cfg(feature = "device-selected")]
 pub mod syscfg;
 #[cfg(feature = "device-selected")]
 pub mod time;
-//#[cfg(feature = "device-selected")]
-//pub mod timer;
+#[cfg(feature = "device-selected")]
+pub mod timer;
+#[cfg(feature = "device-selected")]
+pub mod updater;
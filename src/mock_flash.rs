@@ -0,0 +1,193 @@
+//! Flash in RAM for the tests of the `hal::storage` users
+
+use hal::storage::{Address, AddressOffset, ErasePage, MultiRead, MultiWrite,
+                   Page, StorageSize};
+
+pub const START: u32 = 0x0800_0000;
+pub const PAGE_SIZE: u32 = 64;
+pub const PAGES: u32 = 4;
+pub const SIZE: usize = (PAGE_SIZE * PAGES) as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MockError {
+    OutOfRange,
+    NotErased,
+    PowerLoss,
+}
+
+/// Flash in RAM
+///
+/// Like the STM32F3 flash, a half-word can only be programmed while it
+/// reads 0xFFFF, except for clearing it to 0x0000.
+pub struct MockFlash {
+    pub memory: [u8; SIZE],
+    /// Programmed half-words and erased pages until the power is cut
+    pub budget: Option<u32>,
+}
+
+impl MockFlash {
+    pub fn new() -> Self {
+        MockFlash { memory: [0xFF; SIZE],
+                    budget: None }
+    }
+
+    fn offset(&self,
+              address: u32,
+              len: usize)
+              -> Result<usize, MockError> {
+        let offset = address.wrapping_sub(START) as usize;
+        if offset + len > SIZE {
+            return Err(MockError::OutOfRange);
+        }
+        Ok(offset)
+    }
+
+    fn spend(&mut self) -> Result<(), MockError> {
+        match self.budget {
+            Some(0) => Err(MockError::PowerLoss),
+            Some(n) => {
+                self.budget = Some(n - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl MultiRead<u8, u32> for MockFlash {
+    type Error = MockError;
+
+    fn try_read_slice(&mut self,
+                      address: Address<u32>,
+                      buf: &mut [u8])
+                      -> nb::Result<(), Self::Error> {
+        let offset = self.offset(address.0, buf.len())?;
+        buf.copy_from_slice(&self.memory[offset..offset + buf.len()]);
+        Ok(())
+    }
+}
+
+impl MultiWrite<u16, u32> for MockFlash {
+    type Error = MockError;
+
+    fn try_write_slice(&mut self,
+                       address: Address<u32>,
+                       buf: &mut [u16])
+                       -> nb::Result<(), Self::Error> {
+        let offset = self.offset(address.0, 2 * buf.len())?;
+        assert_eq!(offset % 2, 0, "unaligned half-word");
+        for (i, value) in buf.iter().enumerate() {
+            let at = offset + 2 * i;
+            let current = u16::from(self.memory[at])
+                          | u16::from(self.memory[at + 1]) << 8;
+            if current != 0xFFFF && *value != 0x0000 {
+                return Err(nb::Error::Other(MockError::NotErased));
+            }
+            self.spend()?;
+            self.memory[at] = *value as u8;
+            self.memory[at + 1] = (*value >> 8) as u8;
+        }
+        Ok(())
+    }
+}
+
+impl ErasePage<u32> for MockFlash {
+    type Error = MockError;
+
+    fn try_erase_page(&mut self,
+                      page: Page<u32>)
+                      -> nb::Result<(), Self::Error> {
+        self.try_erase_address(Address(START + page.0 * PAGE_SIZE))
+    }
+
+    fn try_erase_address(&mut self,
+                         address: Address<u32>)
+                         -> nb::Result<(), Self::Error> {
+        let offset = self.offset(address.0, PAGE_SIZE as usize)?;
+        let start = offset - offset % PAGE_SIZE as usize;
+        self.spend()?;
+        let page = &mut self.memory[start..start + PAGE_SIZE as usize];
+        for byte in page.iter_mut() {
+            *byte = 0xFF;
+        }
+        Ok(())
+    }
+}
+
+impl StorageSize<u8, u32> for MockFlash {
+    type Error = MockError;
+
+    fn try_start_address(&mut self)
+                         -> nb::Result<Address<u32>, Self::Error> {
+        Ok(Address(START))
+    }
+
+    fn try_total_size(&mut self)
+                      -> nb::Result<AddressOffset<u32>, Self::Error> {
+        Ok(AddressOffset(SIZE as u32))
+    }
+
+    fn try_page_size(&mut self,
+                     _address: Address<u32>)
+                     -> nb::Result<AddressOffset<u32>, Self::Error> {
+        Ok(AddressOffset(PAGE_SIZE))
+    }
+}
+
+/// Lends the flash to a driver that takes its storage by value
+impl MultiRead<u8, u32> for &mut MockFlash {
+    type Error = MockError;
+
+    fn try_read_slice(&mut self,
+                      address: Address<u32>,
+                      buf: &mut [u8])
+                      -> nb::Result<(), Self::Error> {
+        (**self).try_read_slice(address, buf)
+    }
+}
+
+impl MultiWrite<u16, u32> for &mut MockFlash {
+    type Error = MockError;
+
+    fn try_write_slice(&mut self,
+                       address: Address<u32>,
+                       buf: &mut [u16])
+                       -> nb::Result<(), Self::Error> {
+        (**self).try_write_slice(address, buf)
+    }
+}
+
+impl ErasePage<u32> for &mut MockFlash {
+    type Error = MockError;
+
+    fn try_erase_page(&mut self,
+                      page: Page<u32>)
+                      -> nb::Result<(), Self::Error> {
+        (**self).try_erase_page(page)
+    }
+
+    fn try_erase_address(&mut self,
+                         address: Address<u32>)
+                         -> nb::Result<(), Self::Error> {
+        (**self).try_erase_address(address)
+    }
+}
+
+impl StorageSize<u8, u32> for &mut MockFlash {
+    type Error = MockError;
+
+    fn try_start_address(&mut self) -> nb::Result<Address<u32>, Self::Error> {
+        (**self).try_start_address()
+    }
+
+    fn try_total_size(&mut self)
+                      -> nb::Result<AddressOffset<u32>, Self::Error> {
+        (**self).try_total_size()
+    }
+
+    fn try_page_size(&mut self,
+                     address: Address<u32>)
+                     -> nb::Result<AddressOffset<u32>, Self::Error> {
+        (**self).try_page_size(address)
+    }
+}
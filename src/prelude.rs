@@ -36,5 +36,5 @@ pub use hal::serial::Read as _embedded_hal_serial_Read;
 pub use hal::serial::Write as _embedded_hal_serial_Write;
 pub use hal::spi::FullDuplex as _embedded_hal_spi_FullDuplex;
 pub use hal::timer::CountDown as _embedded_hal_timer_CountDown;
-pub use hal::pwm as _embedded_hal_Pwm;
+pub use hal::pwm::Pwm as _embedded_hal_Pwm;
 pub use hal::pwm::PwmPin as _embedded_hal_PwmPin;
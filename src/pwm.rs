@@ -1,8 +1,9 @@
 //! Pwm
 
 use crate::gpio;
+use crate::pac::{TIM2, TIM3, TIM4};
 use crate::timer;
-use hal;
+use void::Void;
 
 /// pwm
 pub struct PwmBinding<P: gpio::GPIOPin, C: timer::TimerChannel> {
@@ -20,25 +21,30 @@ impl<P: gpio::GPIOPin, C: timer::TimerChannel> PwmBinding<P, C> {
 impl<P: gpio::GPIOPin, C: timer::TimerChannel> hal::pwm::PwmPin
     for PwmBinding<P, C>
 {
+    type Error = Void;
     type Duty = u32;
-    fn try_disable(&mut self) {
-        self.channel.disable()
+
+    fn try_disable(&mut self) -> Result<(), Self::Error> {
+        self.channel.disable();
+        Ok(())
     }
 
-    fn try_enable(&mut self) {
-        self.channel.enable()
+    fn try_enable(&mut self) -> Result<(), Self::Error> {
+        self.channel.enable();
+        Ok(())
     }
 
-    fn try_get_duty(&self) -> u32 {
-        self.channel.read_ccr()
+    fn try_get_duty(&self) -> Result<u32, Self::Error> {
+        Ok(self.channel.read_ccr())
     }
 
-    fn try_get_max_duty(&self) -> u32 {
-        self.channel.read_arr()
+    fn try_get_max_duty(&self) -> Result<u32, Self::Error> {
+        Ok(self.channel.read_arr())
     }
 
-    fn try_set_duty(&mut self, duty: u32) {
-        self.channel.write_ccr(duty)
+    fn try_set_duty(&mut self, duty: u32) -> Result<(), Self::Error> {
+        self.channel.write_ccr(duty);
+        Ok(())
     }
 }
 
@@ -55,39 +61,42 @@ pub trait PwmExt<SP: gpio::OutputSpeed,
     fn to_pwm(self, channel: C, sp: SP) -> Self::Output;
 }
 
-macro_rules! pwm {
-    (
-        $CRFN:ident, $PIN:ident,($TIM:ident, $CHN:ident, $CHPE:expr),($AF:ident, $PP:ident)
-    ) => {
-        impl<SP: gpio::OutputSpeed,
-              PT: gpio::PullType,
-              PM: gpio::PinMode,
-              CM: timer::ChMode>
-            PwmExt<SP, timer::$TIM::Channel<timer::$CHN, CM>, gpio::$AF>
-            for gpio::$PIN<PT, PM>
+/// Pin connected to channel `CH` of `TIM`
+pub trait ChPin<TIM, CH, SP: gpio::OutputSpeed> {
+    /// Alternate function of the channel on the pin
+    type AltFn: gpio::AltFnNum;
+    /// Pin switched to the alternate function
+    type Output: gpio::GPIOPin;
+    /// Switches the pin to the alternate function of channel `CH`
+    fn setup(self, sp: SP) -> Self::Output;
+}
+
+macro_rules! pwm_timer {
+    ($TIMX:ident, $TIM:ident) => {
+        impl<P, SP, CH, CM, AF> PwmExt<SP, timer::$TIM::Channel<CH, CM>, AF>
+            for P
+            where P: ChPin<$TIMX, CH, SP, AltFn = AF> + gpio::GPIOPin,
+                  SP: gpio::OutputSpeed,
+                  CH: timer::ChNum,
+                  CM: timer::ChMode,
+                  AF: gpio::AltFnNum
         {
-            type OutputPin =
-                gpio::$PIN<PT, gpio::AltFn<gpio::$AF, gpio::$PP, SP>>;
-            type Output = PwmBinding<Self::OutputPin,
-                                     timer::$TIM::Channel<timer::$CHN,
-                                                          timer::Pwm1>>;
+            type OutputPin = P::Output;
+            type Output = PwmBinding<P::Output,
+                                     timer::$TIM::Channel<CH, timer::Pwm1>>;
             fn to_pwm(self,
-                      channel: timer::$TIM::Channel<timer::$CHN, CM>,
+                      channel: timer::$TIM::Channel<CH, CM>,
                       sp: SP)
                       -> Self::Output {
-                let pin = self.alternating(gpio::$AF)
-                              .output_speed(sp)
-                              .output_type(gpio::$PP)
-                              .alt_fn(gpio::$AF);
+                let pin = self.setup(sp);
                 let mut channel = channel.mode(timer::Pwm1);
-                channel.preload($CHPE);
+                channel.preload(true);
                 PwmBinding { pin, channel }
             }
         }
 
-        impl<PT: gpio::PullType, PM: gpio::PinMode, CM: timer::ChMode>
-            PwmBinding<gpio::$PIN<PT, PM>,
-                       timer::$TIM::Channel<timer::$CHN, CM>>
+        impl<P: gpio::GPIOPin, CH: timer::ChNum, CM: timer::ChMode>
+            PwmBinding<P, timer::$TIM::Channel<CH, CM>>
         {
             /// Modify channel's preload
             pub fn channel_preload(&mut self, enabled: bool) {
@@ -97,36 +106,132 @@ macro_rules! pwm {
     };
 }
 
+pwm_timer!(TIM2, tim2);
+pwm_timer!(TIM3, tim3);
+pwm_timer!(TIM4, tim4);
+
+macro_rules! pwm {
+    ($TIMX:ident, $PIN:ident, $CHN:ident, ($AF:ident, $PP:ident)) => {
+        impl<PT: gpio::PullType, PM: gpio::PinMode, SP: gpio::OutputSpeed>
+            ChPin<$TIMX, timer::$CHN, SP> for gpio::$PIN<PT, PM>
+        {
+            type AltFn = gpio::$AF;
+            type Output =
+                gpio::$PIN<PT, gpio::AltFn<gpio::$AF, gpio::$PP, SP>>;
+
+            fn setup(self, sp: SP) -> Self::Output {
+                self.alternating(gpio::$AF)
+                    .output_speed(sp)
+                    .output_type(gpio::$PP)
+                    .alt_fn(gpio::$AF)
+            }
+        }
+    };
+}
+
 // XXX: don't force Pwm1? allow Pwm2 as well?
 
-pwm!(bind_pa0_tim2_ch1, PA0, (tim2, CH1, true), (AF1, PushPull));
+// alternate functions from the datasheet
+
+pwm!(TIM2, PA0, CH1, (AF1, PushPull));
+
+pwm!(TIM2, PA5, CH1, (AF1, PushPull));
+
+pwm!(TIM2, PA15, CH1, (AF1, PushPull));
+
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM2, PD3, CH1, (AF2, PushPull));
+
+pwm!(TIM2, PA1, CH2, (AF1, PushPull));
+
+pwm!(TIM2, PB3, CH2, (AF1, PushPull));
+
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM2, PD4, CH2, (AF2, PushPull));
+
+pwm!(TIM2, PA2, CH3, (AF1, PushPull));
+
+pwm!(TIM2, PA9, CH3, (AF10, PushPull));
+
+pwm!(TIM2, PB10, CH3, (AF1, PushPull));
+
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM2, PD7, CH3, (AF2, PushPull));
+
+pwm!(TIM2, PA3, CH4, (AF1, PushPull));
+
+pwm!(TIM2, PA10, CH4, (AF10, PushPull));
+
+pwm!(TIM2, PB11, CH4, (AF1, PushPull));
+
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM2, PD6, CH4, (AF2, PushPull));
+
+pwm!(TIM3, PA6, CH1, (AF2, PushPull));
+
+pwm!(TIM3, PB4, CH1, (AF2, PushPull));
+
+#[cfg(not(feature = "lqfp48"))]
+pwm!(TIM3, PC6, CH1, (AF2, PushPull));
+
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM3, PE2, CH1, (AF2, PushPull));
+
+pwm!(TIM3, PA4, CH2, (AF2, PushPull));
+
+pwm!(TIM3, PA7, CH2, (AF2, PushPull));
+
+pwm!(TIM3, PB5, CH2, (AF2, PushPull));
+
+#[cfg(not(feature = "lqfp48"))]
+pwm!(TIM3, PC7, CH2, (AF2, PushPull));
+
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM3, PE3, CH2, (AF2, PushPull));
+
+pwm!(TIM3, PB0, CH3, (AF2, PushPull));
+
+#[cfg(not(feature = "lqfp48"))]
+pwm!(TIM3, PC8, CH3, (AF2, PushPull));
+
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM3, PE4, CH3, (AF2, PushPull));
+
+pwm!(TIM3, PB1, CH4, (AF2, PushPull));
 
-pwm!(bind_pa1_tim2_ch2, PA1, (tim2, CH2, true), (AF1, PushPull));
+pwm!(TIM3, PB7, CH4, (AF10, PushPull));
 
-pwm!(bind_pa2_tim2_ch3, PA2, (tim2, CH3, true), (AF1, PushPull));
+#[cfg(not(feature = "lqfp48"))]
+pwm!(TIM3, PC9, CH4, (AF2, PushPull));
 
-pwm!(bind_pa3_tim2_ch4, PA3, (tim2, CH4, true), (AF1, PushPull));
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM3, PE5, CH4, (AF2, PushPull));
 
-pwm!(bind_pa6_tim3_ch1, PA6, (tim3, CH1, true), (AF2, PushPull));
+pwm!(TIM4, PA11, CH1, (AF10, PushPull));
 
-pwm!(bind_pa7_tim3_ch2, PA7, (tim3, CH2, true), (AF2, PushPull));
+pwm!(TIM4, PB6, CH1, (AF2, PushPull));
 
-pwm!(bind_pc6_tim3_ch1, PC6, (tim3, CH1, true), (AF2, PushPull));
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM4, PD12, CH1, (AF2, PushPull));
 
-pwm!(bind_pc7_tim3_ch1, PC7, (tim3, CH2, true), (AF2, PushPull));
+pwm!(TIM4, PA12, CH2, (AF10, PushPull));
 
-pwm!(bind_pc8_tim3_ch1, PC8, (tim3, CH3, true), (AF2, PushPull));
+pwm!(TIM4, PB7, CH2, (AF2, PushPull));
 
-pwm!(bind_pc9_tim3_ch1, PC9, (tim3, CH4, true), (AF2, PushPull));
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM4, PD13, CH2, (AF2, PushPull));
 
-pwm!(bind_pb0_tim3_ch3, PB0, (tim3, CH3, true), (AF2, PushPull));
+pwm!(TIM4, PA13, CH3, (AF10, PushPull));
 
-pwm!(bind_pb1_tim3_ch4, PB1, (tim3, CH4, true), (AF2, PushPull));
+pwm!(TIM4, PB8, CH3, (AF2, PushPull));
 
-pwm!(bind_pb6_tim4_ch1, PB6, (tim4, CH1, true), (AF2, PushPull));
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM4, PD14, CH3, (AF2, PushPull));
 
-pwm!(bind_pb7_tim4_ch2, PB7, (tim4, CH2, true), (AF2, PushPull));
+pwm!(TIM4, PB9, CH4, (AF2, PushPull));
 
-pwm!(bind_pb8_tim4_ch3, PB8, (tim4, CH3, true), (AF2, PushPull));
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM4, PD15, CH4, (AF2, PushPull));
 
-pwm!(bind_pb9_tim4_ch4, PB9, (tim4, CH4, true), (AF2, PushPull));
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+pwm!(TIM4, PF6, CH4, (AF2, PushPull));
@@ -1,11 +1,12 @@
 //! Reset and Clock Control
 
-use core::cmp;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::pac::{rcc, RCC};
+use crate::pac::{rcc, PWR, RCC};
 use cast::u32;
 
 use crate::flash::ACR;
+use crate::gpio::{AltFn, HighSpeed, PinMode, PullType, PushPull, AF0, PA8};
 use crate::time::Hertz;
 
 /// Extension trait that constrains the `RCC` peripheral
@@ -20,11 +21,7 @@ impl RccExt for RCC {
         Rcc { ahb: AHB { _0: () },
               apb1: APB1 { _0: () },
               apb2: APB2 { _0: () },
-              cfgr: CFGR { hse: None,
-                           hclk: None,
-                           pclk1: None,
-                           pclk2: None,
-                           sysclk: None } }
+              cfgr: CFGR::reset() }
     }
 }
 
@@ -84,18 +81,28 @@ pub struct APB2 {
 }
 
 impl APB2 {
+    pub(crate) fn internal_get() -> Self {
+        APB2 { _0: () }
+    }
+
     pub(crate) fn enr(&mut self) -> &rcc::APB2ENR {
         // NOTE(unsafe) this proxy grants exclusive access to this register
         unsafe { &(*RCC::ptr()).apb2enr }
     }
+
+    pub(crate) fn rstr(&mut self) -> &rcc::APB2RSTR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).apb2rstr }
+    }
 }
 
 /// HSE Configuration of clock, divider and bypass
 struct HseConfig {
     /// Clock speed of HSE
     speed: u32,
-    /// Divider to be used, output will be HSE / divider
-    divider: u32,
+    /// Divider to be used, output will be HSE / divider, `None` lets the PLL
+    /// solver pick one
+    divider: Option<u32>,
     /// If the clock driving circuitry is bypassed i.e. using an oscillator,
     /// not a crystal or resonator
     bypass: bool,
@@ -112,6 +119,8 @@ pub enum HseBypass {
 
 /// HSE divide selector
 pub enum HseDivider {
+    /// Let the PLL solver pick the divider
+    Auto,
     /// Do not divide HSE clock
     NoDivision,
     /// Divide HSE clock by 2
@@ -147,6 +156,29 @@ pub enum HseDivider {
 }
 
 const HSI: u32 = 8_000_000; // Hz
+const LSE: u32 = 32_768; // Hz
+const LSE_BYPASS_MAX: u32 = 1_000_000; // Hz
+const LSI: u32 = 40_000; // Hz
+// ready flag polls, LSE crystal may take up to 2 s to start
+const LSE_READY_TIMEOUT: u32 = 20_000_000;
+const LSI_READY_TIMEOUT: u32 = 100_000;
+const HSI_READY_TIMEOUT: u32 = 100_000;
+const HSE_READY_TIMEOUT: u32 = 100_000;
+const PLL_READY_TIMEOUT: u32 = 100_000;
+const SWS_TIMEOUT: u32 = 100_000;
+const DBP_TIMEOUT: u32 = 100_000;
+const HSE_MIN: u32 = 4_000_000; // Hz
+const HSE_BYPASS_MIN: u32 = 1_000_000; // Hz
+const HSE_MAX: u32 = 32_000_000; // Hz
+const SYSCLK_MAX: u32 = 72_000_000; // Hz
+const PCLK1_MAX: u32 = 36_000_000; // Hz
+const PLLIN_MIN: u32 = 1_000_000; // Hz
+const PLLIN_MAX: u32 = 24_000_000; // Hz
+const PLLOUT_MIN: u32 = 16_000_000; // Hz
+const PLLOUT_MAX: u32 = 72_000_000; // Hz
+const USBCLK: u32 = 48_000_000; // Hz
+const USB_PLL_DIV1: u32 = 48_000_000; // Hz
+const USB_PLL_DIV1_5: u32 = 72_000_000; // Hz
 
 /// Clock configuration
 pub struct CFGR {
@@ -155,9 +187,29 @@ pub struct CFGR {
     pclk1: Option<u32>,
     pclk2: Option<u32>,
     sysclk: Option<u32>,
+    usb: bool,
+    kernel: KernelClocks,
+    lse: Option<Lse>,
+    lsi: bool,
+    rtc: Option<RtcClockSource>,
+    css: bool,
 }
 
 impl CFGR {
+    fn reset() -> Self {
+        CFGR { hse: None,
+               hclk: None,
+               pclk1: None,
+               pclk2: None,
+               sysclk: None,
+               usb: false,
+               kernel: KernelClocks::reset(),
+               lse: None,
+               lsi: false,
+               rtc: None,
+               css: false }
+    }
+
     /// Sets a HseConfig that checks that the HSE divider is valid and if the
     /// clock is in bypass mode
     pub fn hse<F>(mut self,
@@ -169,22 +221,23 @@ impl CFGR {
     {
         self.hse = Some(HseConfig { speed: freq.into().0,
                                     divider: match divider {
-                                        HseDivider::NoDivision => 1,
-                                        HseDivider::DivideBy2 => 2,
-                                        HseDivider::DivideBy3 => 3,
-                                        HseDivider::DivideBy4 => 4,
-                                        HseDivider::DivideBy5 => 5,
-                                        HseDivider::DivideBy6 => 6,
-                                        HseDivider::DivideBy7 => 7,
-                                        HseDivider::DivideBy8 => 8,
-                                        HseDivider::DivideBy9 => 9,
-                                        HseDivider::DivideBy10 => 10,
-                                        HseDivider::DivideBy11 => 11,
-                                        HseDivider::DivideBy12 => 12,
-                                        HseDivider::DivideBy13 => 13,
-                                        HseDivider::DivideBy14 => 14,
-                                        HseDivider::DivideBy15 => 15,
-                                        HseDivider::DivideBy16 => 16,
+                                        HseDivider::Auto => None,
+                                        HseDivider::NoDivision => Some(1),
+                                        HseDivider::DivideBy2 => Some(2),
+                                        HseDivider::DivideBy3 => Some(3),
+                                        HseDivider::DivideBy4 => Some(4),
+                                        HseDivider::DivideBy5 => Some(5),
+                                        HseDivider::DivideBy6 => Some(6),
+                                        HseDivider::DivideBy7 => Some(7),
+                                        HseDivider::DivideBy8 => Some(8),
+                                        HseDivider::DivideBy9 => Some(9),
+                                        HseDivider::DivideBy10 => Some(10),
+                                        HseDivider::DivideBy11 => Some(11),
+                                        HseDivider::DivideBy12 => Some(12),
+                                        HseDivider::DivideBy13 => Some(13),
+                                        HseDivider::DivideBy14 => Some(14),
+                                        HseDivider::DivideBy15 => Some(15),
+                                        HseDivider::DivideBy16 => Some(16),
                                     },
                                     bypass: match bypass {
                                         HseBypass::Disable => false,
@@ -226,37 +279,178 @@ impl CFGR {
         self
     }
 
-    /// Freezes the clock configuration, making it effective
-    pub fn freeze(self, acr: &mut ACR) -> Clocks {
-        let pllmul = match &self.hse {
-            Some(hse_cfg) => {
-                let hse = hse_cfg.speed / hse_cfg.divider;
-                self.sysclk.unwrap_or(hse) / hse // HSE has settable divider
+    /// Requires a 48 MHz USB clock
+    ///
+    /// Constrains the PLL to 48 or 72 MHz so that USBPRE can derive the USB
+    /// clock from it. The PLL must be fed by HSE and drives SYSCLK, which
+    /// defaults to 48 MHz if not set.
+    pub fn require_usb_clock(mut self) -> Self {
+        self.usb = true;
+        self
+    }
+
+    /// Selects the USART1 kernel clock (CFGR3 USART1SW)
+    pub fn usart1_clock(mut self, src: UsartClockSource) -> Self {
+        self.kernel.usart1 = src;
+        self
+    }
+
+    /// Selects the USART2 kernel clock (CFGR3 USART2SW)
+    pub fn usart2_clock(mut self, src: UsartClockSource) -> Self {
+        self.kernel.usart2 = src;
+        self
+    }
+
+    /// Selects the USART3 kernel clock (CFGR3 USART3SW)
+    pub fn usart3_clock(mut self, src: UsartClockSource) -> Self {
+        self.kernel.usart3 = src;
+        self
+    }
+
+    /// Selects the I2C1 kernel clock (CFGR3 I2C1SW)
+    pub fn i2c1_clock(mut self, src: I2cClockSource) -> Self {
+        self.kernel.i2c1 = src;
+        self
+    }
+
+    /// Selects the I2C2 kernel clock (CFGR3 I2C2SW)
+    pub fn i2c2_clock(mut self, src: I2cClockSource) -> Self {
+        self.kernel.i2c2 = src;
+        self
+    }
+
+    /// Selects the TIM1 kernel clock (CFGR3 TIM1SW)
+    pub fn tim1_clock(mut self, src: TimClockSource) -> Self {
+        self.kernel.tim1 = src;
+        self
+    }
+
+    /// Selects the TIM8 kernel clock (CFGR3 TIM8SW)
+    #[cfg(feature = "stm32f303")]
+    pub fn tim8_clock(mut self, src: TimClockSource) -> Self {
+        self.kernel.tim8 = src;
+        self
+    }
+
+    /// Sets the ADC1/ADC2 PLL clock prescaler (CFGR2 ADC12PRES)
+    pub fn adc12_clock(mut self, pres: AdcPrescaler) -> Self {
+        self.kernel.adc12 = pres;
+        self
+    }
+
+    /// Sets the ADC3/ADC4 PLL clock prescaler (CFGR2 ADC34PRES)
+    #[cfg(feature = "stm32f303")]
+    pub fn adc34_clock(mut self, pres: AdcPrescaler) -> Self {
+        self.kernel.adc34 = pres;
+        self
+    }
+
+    /// Enables the low speed external oscillator
+    pub fn lse(mut self, lse: Lse) -> Self {
+        self.lse = Some(lse);
+        self
+    }
+
+    /// Enables the low speed internal oscillator
+    pub fn lsi(mut self, _lsi: Lsi) -> Self {
+        self.lsi = true;
+        self
+    }
+
+    /// Selects and enables the RTC clock (BDCR RTCSEL)
+    ///
+    /// The selected oscillator has to be enabled as well. Once set, RTCSEL can
+    /// only be changed by a backup domain reset, so freezing fails with
+    /// [`ClockError::RtcSourceLocked`] if another source is already selected.
+    ///
+    /// [`ClockError::RtcSourceLocked`]: enum.ClockError.html#variant.RtcSourceLocked
+    pub fn rtc_clock(mut self, src: RtcClockSource) -> Self {
+        self.rtc = Some(src);
+        self
+    }
+
+    /// Enables the clock security system
+    ///
+    /// If HSE fails, hardware switches SYSCLK to HSI, stops the PLL and raises
+    /// NMI. Call [`css_nmi_handler`] from the NMI handler and
+    /// [`take_hse_failure`] afterwards to reconfigure clocks on HSI.
+    ///
+    /// [`css_nmi_handler`]: fn.css_nmi_handler.html
+    /// [`take_hse_failure`]: fn.take_hse_failure.html
+    pub fn enable_css(mut self) -> Self {
+        self.css = true;
+        self
+    }
+
+    /// Calculates the clock tree for this configuration without touching any
+    /// register
+    pub fn plan(&self) -> Result<ClockPlan, ClockError> {
+        if let Some(hse_cfg) = &self.hse {
+            let hse_min = if hse_cfg.bypass { HSE_BYPASS_MIN } else { HSE_MIN };
+            if hse_cfg.speed < hse_min || hse_cfg.speed > HSE_MAX {
+                return Err(ClockError::HseOutOfRange);
             }
-            None => (2 * self.sysclk.unwrap_or(HSI)) / HSI, /* HSI is always divided by 2 */
+        }
+
+        // oscillator that can drive SYSCLK directly, without the PLL
+        let (osc, osc_source) = match &self.hse {
+            Some(hse_cfg) => (hse_cfg.speed, SysClkSource::Hse),
+            None => (HSI, SysClkSource::Hsi),
         };
 
-        let pllmul = cmp::min(cmp::max(pllmul, 2), 16);
-        let pllmul_bits = if pllmul == 2 {
-            None
-        } else {
-            Some(pllmul as u8 - 2)
+        if self.usb && self.hse.is_none() {
+            return Err(ClockError::UsbRequiresHse);
+        }
+
+        if self.css && self.hse.is_none() {
+            return Err(ClockError::HseNotEnabled);
+        }
+
+        if let Some(Lse { speed, bypass: true, .. }) = self.lse {
+            if speed > LSE_BYPASS_MAX {
+                return Err(ClockError::LseOutOfRange);
+            }
+        }
+        let lse = self.lse.map(|lse| lse.speed);
+        let lsi = if self.lsi { Some(LSI) } else { None };
+        let rtcclk = match self.rtc {
+            Some(RtcClockSource::Lse) => {
+                Some(lse.ok_or(ClockError::LseNotEnabled)?)
+            }
+            Some(RtcClockSource::Lsi) => {
+                Some(lsi.ok_or(ClockError::LsiNotEnabled)?)
+            }
+            Some(RtcClockSource::HseDiv32) => match &self.hse {
+                Some(hse_cfg) => Some(hse_cfg.speed / 32),
+                None => return Err(ClockError::HseNotEnabled),
+            },
+            None => None,
+        };
+
+        let pll_target = match self.sysclk {
+            Some(sysclk) if sysclk != osc || self.usb => Some(sysclk),
+            None if self.usb => Some(USB_PLL_DIV1),
+            _ => None,
         };
 
-        let sysclk = match &self.hse {
-            Some(hse_cfg) => {
-                let hse = hse_cfg.speed / hse_cfg.divider;
-                pllmul * hse
+        let (source, pll, sysclk) = match pll_target {
+            Some(sysclk) => {
+                if sysclk > SYSCLK_MAX {
+                    return Err(ClockError::SysclkUnreachable);
+                }
+                let pll = solve_pll(self.hse.as_ref(), sysclk, self.usb)?;
+                (SysClkSource::Pll, Some(pll), pll.freq)
             }
-            None => pllmul * HSI / 2,
+            None => (osc_source, None, osc),
         };
 
-        assert!(sysclk <= 72_000_000);
+        // USBPRE: PLL / 1 when PLL runs at 48 MHz, PLL / 1.5 at 72 MHz
+        let usbpre_div1 = sysclk == USB_PLL_DIV1;
+        let usbclk = if self.usb { Some(USBCLK) } else { None };
 
-        let hpre_bits = self.hclk
-                            .map(|hclk| {
-                                match sysclk / hclk {
-                0 => panic!("Requested HCLK is higher than generated SYSCLK"),
+        let hpre_bits = match self.hclk {
+            Some(hclk) => match sysclk / hclk {
+                0 => return Err(ClockError::HclkUnreachable),
                 1 => 0b0111,
                 2 => 0b1000,
                 3..=5 => 0b1001,
@@ -266,165 +460,983 @@ impl CFGR {
                 96..=191 => 0b1101,
                 192..=383 => 0b1110,
                 _ => 0b1111,
-            }
-                            })
-                            .unwrap_or(0b0111);
+            },
+            None => 0b0111,
+        };
 
         let hclk = sysclk / (1 << (hpre_bits - 0b0111));
 
-        assert!(hclk <= 72_000_000);
-
-        let ppre1_bits = self.pclk1
-                             .map(|pclk1| {
-                                 match hclk / pclk1 {
-                0 => panic!("Requested PCLK1 is higher than generated HCLK"),
-                1 => 0b011,
-                2 => 0b100,
-                3..=5 => 0b101,
-                6..=11 => 0b110,
-                _ => 0b111,
-            }
-                             })
-                             .unwrap_or(0b011);
-
+        let ppre1_bits =
+            ppre_bits(hclk, self.pclk1).ok_or(ClockError::Pclk1Unreachable)?;
         let ppre1 = 1 << (ppre1_bits - 0b011);
         let pclk1 = hclk / u32(ppre1);
 
-        assert!(pclk1 <= 36_000_000);
-
-        let ppre2_bits = self.pclk2
-                             .map(|pclk2| {
-                                 match hclk / pclk2 {
-                0 => panic!("Requested PCLK2 is higher than generated HCLK"),
-                1 => 0b011,
-                2 => 0b100,
-                3..=5 => 0b101,
-                6..=11 => 0b110,
-                _ => 0b111,
-            }
-                             })
-                             .unwrap_or(0b011);
+        if pclk1 > PCLK1_MAX {
+            return Err(ClockError::Pclk1OverLimit);
+        }
 
+        let ppre2_bits =
+            ppre_bits(hclk, self.pclk2).ok_or(ClockError::Pclk2Unreachable)?;
         let ppre2 = 1 << (ppre2_bits - 0b011);
         let pclk2 = hclk / u32(ppre2);
 
-        assert!(pclk2 <= 72_000_000);
+        let latency_bits = latency_bits(sysclk);
 
-        // adjust flash wait states
-        unsafe {
-            acr.acr().write(|w| {
-                         w.latency().bits(if sysclk <= 24_000_000 {
-                                              0b000
-                                          } else if sysclk <= 48_000_000 {
-                                              0b001
-                                          } else {
-                                              0b010
-                                          })
-                     })
-        }
+        let kernel = self.kernel;
+        let usartclk = |src: UsartClockSource, pclk: u32| match src {
+            UsartClockSource::Pclk => Ok(pclk),
+            UsartClockSource::Sysclk => Ok(sysclk),
+            UsartClockSource::Lse => lse.ok_or(ClockError::LseNotEnabled),
+            UsartClockSource::Hsi => Ok(HSI),
+        };
+        let i2cclk = |src: I2cClockSource| match src {
+            I2cClockSource::Hsi => HSI,
+            I2cClockSource::Sysclk => sysclk,
+        };
+        // PLL x2 is only available with PLL driving undivided AHB and APB2
+        let pllx2 = if source == SysClkSource::Pll
+                       && hpre_bits == 0b0111
+                       && ppre2 == 1
+        {
+            Some(sysclk * 2)
+        } else {
+            None
+        };
+        let apb2_timclk = if ppre2 == 1 { pclk2 } else { pclk2 * 2 };
+        let timclk = |src: TimClockSource| match src {
+            TimClockSource::Pclk => Ok(apb2_timclk),
+            TimClockSource::PllX2 => pllx2.ok_or(ClockError::PllX2Unavailable),
+        };
+        let adcclk = |pres: AdcPrescaler| match (pres.divider(), &pll) {
+            (None, _) => Ok(None),
+            (Some(div), Some(pll)) => Ok(Some(Hertz(pll.freq / div))),
+            (Some(_), None) => Err(ClockError::AdcPllUnavailable),
+        };
+
+        let clocks = Clocks { hclk: Hertz(hclk),
+                              pclk1: Hertz(pclk1),
+                              pclk2: Hertz(pclk2),
+                              ppre1,
+                              ppre2,
+                              pll,
+                              sysclk: Hertz(sysclk),
+                              usbclk: usbclk.map(Hertz),
+                              usart1clk: Hertz(usartclk(kernel.usart1, pclk2)?),
+                              usart2clk: Hertz(usartclk(kernel.usart2, pclk1)?),
+                              usart3clk: Hertz(usartclk(kernel.usart3, pclk1)?),
+                              i2c1clk: Hertz(i2cclk(kernel.i2c1)),
+                              i2c2clk: Hertz(i2cclk(kernel.i2c2)),
+                              tim1clk: Hertz(timclk(kernel.tim1)?),
+                              #[cfg(feature = "stm32f303")]
+                              tim8clk: Hertz(timclk(kernel.tim8)?),
+                              adc12clk: adcclk(kernel.adc12)?,
+                              #[cfg(feature = "stm32f303")]
+                              adc34clk: adcclk(kernel.adc34)?,
+                              lse: lse.map(Hertz),
+                              lsi: lsi.map(Hertz),
+                              rtcclk: rtcclk.map(Hertz) };
+
+        Ok(ClockPlan { source,
+                       hpre_bits,
+                       ppre1_bits,
+                       ppre2_bits,
+                       latency_bits,
+                       usbpre_div1,
+                       kernel,
+                       clocks })
+    }
+
+    /// Freezes the clock configuration, making it effective
+    ///
+    /// Panics if the requested configuration can't be achieved, see
+    /// [`try_freeze`](#method.try_freeze) for a fallible version.
+    pub fn freeze(self, acr: &mut ACR) -> Clocks {
+        self.try_freeze(acr).unwrap()
+    }
+
+    /// Freezes the clock configuration, making it effective
+    ///
+    /// Returns an error if the requested configuration can't be achieved.
+    /// Planning errors are reported before any register is touched, an
+    /// oscillator or switch timeout may leave SYSCLK running from HSI.
+    pub fn try_freeze(self, acr: &mut ACR) -> Result<Clocks, ClockError> {
+        let plan = self.plan()?;
 
         let rcc = unsafe { &*RCC::ptr() };
+
+        // RTCSEL is write once, refuse before the clock tree is touched
+        if let Some(rtc) = self.rtc {
+            let rtcsel = rcc.bdcr.read().rtcsel().bits();
+            if rtcsel != 0b00 && rtcsel != rtc.bits() {
+                return Err(ClockError::RtcSourceLocked);
+            }
+        }
+
+        // a missing LSE crystal is reported before SYSCLK is switched
+        if let Some(lse) = &self.lse {
+            with_backup_domain(|bdcr| lse.enable(bdcr))??;
+        }
+
+        // more flash wait states have to be in place before SYSCLK goes up
+        let latency_bits = acr.acr().read().latency().bits();
+        if plan.latency_bits > latency_bits {
+            acr.acr()
+               .modify(|_, w| unsafe { w.latency().bits(plan.latency_bits) });
+        }
+
+        // run from HSI while PLL and HSE are reconfigured
+        rcc.cr.modify(|_, w| w.hsion().set_bit());
+        if !wait_ready(HSI_READY_TIMEOUT, || rcc.cr.read().hsirdy().bit_is_set())
+        {
+            return Err(ClockError::HsiTimeout);
+        }
+        let hsi_bits = SysClkSource::Hsi.sw_bits();
+        if rcc.cfgr.read().sws().bits() != hsi_bits {
+            rcc.cfgr.modify(|_, w| unsafe { w.sw().bits(hsi_bits) });
+            if !wait_ready(SWS_TIMEOUT, || {
+                rcc.cfgr.read().sws().bits() == hsi_bits
+            }) {
+                return Err(ClockError::SwitchTimeout);
+            }
+        }
+
+        // PLL can only be configured while it's off
+        rcc.cr.modify(|_, w| w.pllon().clear_bit());
+        if !wait_ready(PLL_READY_TIMEOUT, || {
+            rcc.cr.read().pllrdy().bit_is_clear()
+        }) {
+            return Err(ClockError::PllTimeout);
+        }
+
+        // CSS must only be on while HSE is
+        rcc.cr.modify(|_, w| w.csson().clear_bit());
+
         // If HSE is available, set it up
         if let Some(hse_cfg) = &self.hse {
-            if hse_cfg.bypass {
-                // Bypass clock
-                rcc.cr.write(|w| w.hseon().set_bit().hsebyp().set_bit());
-            } else {
-                rcc.cr.write(|w| w.hseon().set_bit());
+            // HSEBYP can only be written while HSE is off
+            let cr = rcc.cr.read();
+            if cr.hseon().bit_is_set() && cr.hsebyp().bit() != hse_cfg.bypass {
+                rcc.cr.modify(|_, w| w.hseon().clear_bit());
+                if !wait_ready(HSE_READY_TIMEOUT, || {
+                    rcc.cr.read().hserdy().bit_is_clear()
+                }) {
+                    return Err(ClockError::HseTimeout);
+                }
             }
+            rcc.cr.modify(|_, w| w.hsebyp().bit(hse_cfg.bypass));
+            rcc.cr.modify(|_, w| w.hseon().set_bit());
 
-            // WARNING! Bit 0 in cfgr2 is connected to bit 17 in cfgr (due to
-            // MCU compatibility), if bit 0 is set here it must also
-            // be set in any subsequent write to cfgr and vise-versa
-            rcc.cfgr2
-               .write(|w| w.prediv().bits(hse_cfg.divider as u8 - 1));
-
-            while rcc.cr.read().hserdy().bit_is_clear() {}
+            if !wait_ready(HSE_READY_TIMEOUT, || {
+                rcc.cr.read().hserdy().bit_is_set()
+            }) {
+                rcc.cr.modify(|_, w| w.hseon().clear_bit());
+                return Err(ClockError::HseTimeout);
+            }
+        } else {
+            rcc.cr.modify(|_, w| w.hseon().clear_bit());
         }
 
-        if let Some(pllmul_bits) = pllmul_bits {
+        let prediv_bits =
+            plan.clocks.pll.map(|pll| pll.prediv - 1).unwrap_or(0);
+        // WARNING! Bit 0 in cfgr2 is connected to bit 17 in cfgr (due to
+        // MCU compatibility), if bit 0 is set here it must also
+        // be set in any subsequent write to cfgr and vise-versa
+        rcc.cfgr2.write(|w| unsafe {
+                     w.prediv()
+                      .bits(prediv_bits)
+                      .adc12pres()
+                      .bits(plan.kernel.adc12.bits())
+                 });
+        #[cfg(feature = "stm32f303")]
+        rcc.cfgr2.modify(|_, w| unsafe {
+                      w.adc34pres().bits(plan.kernel.adc34.bits())
+                  });
+
+        if let Some(pll) = &plan.clocks.pll {
             // use PLL as source
+            let pllmul_bits = pll.pllmul - 2;
 
-            if let Some(_) = &self.hse {
-                // HSE as PLL input
-                rcc.cfgr.modify(|_, w| {
-                            w.pllsrc()
-                             .hse_div_prediv()
-                             .pllmul()
-                             .bits(pllmul_bits)
-                        });
-            } else {
-                // HSI as PLL input
-                rcc.cfgr.write(|w| w.pllmul().bits(pllmul_bits));
+            match pll.src {
+                PllSrc::HsiDiv2 => {
+                    rcc.cfgr.modify(|_, w| {
+                                w.pllsrc()
+                                 .hsi_div2()
+                                 .pllmul()
+                                 .bits(pllmul_bits)
+                            });
+                }
+                #[cfg(any(feature = "stm32f303xd", feature = "stm32f303xe"))]
+                PllSrc::HsiDivPrediv => {
+                    rcc.cfgr.modify(|_, w| {
+                                w.pllsrc()
+                                 .hsi_div_prediv()
+                                 .pllmul()
+                                 .bits(pllmul_bits)
+                            });
+                }
+                PllSrc::HseDivPrediv => {
+                    rcc.cfgr.modify(|_, w| {
+                                w.pllsrc()
+                                 .hse_div_prediv()
+                                 .pllmul()
+                                 .bits(pllmul_bits)
+                            });
+                }
             }
 
             rcc.cr.modify(|_, w| w.pllon().set_bit());
 
-            while rcc.cr.read().pllrdy().bit_is_clear() {}
-
-            // SW: PLL selected as system clock
-            rcc.cfgr.modify(|_, w| unsafe {
-                        w.ppre2()
-                         .bits(ppre2_bits)
-                         .ppre1()
-                         .bits(ppre1_bits)
-                         .hpre()
-                         .bits(hpre_bits)
-                         .sw()
-                         .bits(0b10)
-                    });
-        } else {
-            let sw_bits = if let Some(_) = &self.hse {
-                // use HSE as source
-                0b01
-            } else {
-                // use HSI as source
-                0b00
-            };
+            if !wait_ready(PLL_READY_TIMEOUT, || {
+                rcc.cr.read().pllrdy().bit_is_set()
+            }) {
+                rcc.cr.modify(|_, w| w.pllon().clear_bit());
+                return Err(ClockError::PllTimeout);
+            }
+        }
+
+        // SW: selected system clock source
+        rcc.cfgr.modify(|_, w| unsafe {
+                    w.usbpre()
+                     .bit(plan.usbpre_div1)
+                     .ppre2()
+                     .bits(plan.ppre2_bits)
+                     .ppre1()
+                     .bits(plan.ppre1_bits)
+                     .hpre()
+                     .bits(plan.hpre_bits)
+                     .sw()
+                     .bits(plan.source.sw_bits())
+                });
 
-            rcc.cfgr.write(|w| unsafe {
-                        w.ppre2()
-                         .bits(ppre2_bits)
-                         .ppre1()
-                         .bits(ppre1_bits)
-                         .hpre()
-                         .bits(hpre_bits)
-                         .sw()
-                         .bits(sw_bits)
-                    });
+        if !wait_ready(SWS_TIMEOUT, || {
+            rcc.cfgr.read().sws().bits() == plan.source.sw_bits()
+        }) {
+            return Err(ClockError::SwitchTimeout);
         }
 
-        // Running from HSE or PLL fed by HSE, disable HSI
-        if let Some(_) = &self.hse {
+        // fewer flash wait states only once SYSCLK went down
+        if plan.latency_bits < latency_bits {
+            acr.acr()
+               .modify(|_, w| unsafe { w.latency().bits(plan.latency_bits) });
+        }
+
+        rcc.cr.modify(|_, w| w.csson().bit(self.css));
+
+        if self.lsi {
+            rcc.csr.modify(|_, w| w.lsion().set_bit());
+            if !wait_ready(LSI_READY_TIMEOUT, || {
+                rcc.csr.read().lsirdy().bit_is_set()
+            }) {
+                return Err(ClockError::LsiTimeout);
+            }
+        }
+
+        if let Some(rtc) = self.rtc {
+            with_backup_domain(|bdcr| {
+                bdcr.modify(|_, w| unsafe {
+                        w.rtcsel().bits(rtc.bits()).rtcen().set_bit()
+                    })
+            })?;
+        }
+
+        // peripheral kernel clocks, sources are running by now
+        rcc.cfgr3.modify(|_, w| unsafe {
+                     w.usart1sw()
+                      .bits(plan.kernel.usart1.bits())
+                      .usart2sw()
+                      .bits(plan.kernel.usart2.bits())
+                      .usart3sw()
+                      .bits(plan.kernel.usart3.bits())
+                      .i2c1sw()
+                      .bit(plan.kernel.i2c1 == I2cClockSource::Sysclk)
+                      .i2c2sw()
+                      .bit(plan.kernel.i2c2 == I2cClockSource::Sysclk)
+                      .tim1sw()
+                      .bit(plan.kernel.tim1 == TimClockSource::PllX2)
+                  });
+        #[cfg(feature = "stm32f303")]
+        rcc.cfgr3.modify(|_, w| {
+                     w.tim8sw()
+                      .bit(plan.kernel.tim8 == TimClockSource::PllX2)
+                 });
+
+        // Running from HSE or PLL fed by HSE, disable HSI unless a peripheral
+        // is clocked from it
+        if self.hse.is_some() && !plan.kernel.uses_hsi() {
             rcc.cr.modify(|_, w| w.hsion().clear_bit());
         }
 
-        Clocks { hclk: Hertz(hclk),
-                 pclk1: Hertz(pclk1),
-                 pclk2: Hertz(pclk2),
-                 ppre1,
-                 ppre2,
-                 sysclk: Hertz(sysclk) }
+        Ok(plan.clocks)
+    }
+
+    /// Switches the clocks of a running system to this configuration
+    ///
+    /// SYSCLK is moved to HSI while PLL and HSE are reconfigured, flash wait
+    /// states are raised before and lowered after the switch. Drivers have to
+    /// be told about the new clocks through the returned
+    /// [`ClockTransition`](struct.ClockTransition.html).
+    pub fn try_transition(self,
+                          acr: &mut ACR,
+                          from: Clocks)
+                          -> Result<ClockTransition, ClockError> {
+        let to = self.try_freeze(acr)?;
+        Ok(ClockTransition { from, to })
+    }
+}
+
+/// Change of the clock configuration at runtime
+#[derive(Clone, Copy, Debug)]
+pub struct ClockTransition {
+    from: Clocks,
+    to: Clocks,
+}
+
+impl ClockTransition {
+    /// Returns the clocks before the transition
+    pub fn from(&self) -> Clocks {
+        self.from
+    }
+
+    /// Returns the clocks after the transition
+    pub fn to(&self) -> Clocks {
+        self.to
+    }
+
+    /// Recomputes the clock dependent settings of `driver`
+    pub fn notify<D: Reclock>(&self,
+                              driver: &mut D)
+                              -> Result<&Self, D::Error> {
+        driver.reclock(self.to)?;
+        Ok(self)
+    }
+}
+
+/// Driver whose timing is derived from the clocks
+pub trait Reclock {
+    /// Reason the driver can't run from the new clocks
+    type Error;
+
+    /// Recomputes baud rate, prescalers and the like for new `clocks`
+    ///
+    /// The driver keeps its previous settings if an error is returned.
+    fn reclock(&mut self, clocks: Clocks) -> Result<(), Self::Error>;
+}
+
+static HSE_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Acknowledges a clock security system interrupt, call it from the NMI
+/// handler
+///
+/// Returns `true` if CSS detected an HSE failure. By then hardware has
+/// switched SYSCLK to HSI and stopped the PLL, so all `Clocks` are stale until
+/// the application reconfigures through [`take_hse_failure`]. Only clears
+/// CSSF and sets an atomic flag, so it's safe to call from NMI.
+///
+/// [`take_hse_failure`]: fn.take_hse_failure.html
+pub fn css_nmi_handler() -> bool {
+    // NOTE(unsafe) CIR holds no state besides the interrupt enables, which
+    // are written back as they are
+    let rcc = unsafe { &*RCC::ptr() };
+    let cir = rcc.cir.read();
+    if cir.cssf().bit_is_clear() {
+        return false;
+    }
+    rcc.cir.write(|w| unsafe {
+                   w.bits(cir.bits() & CIR_ENABLE_MASK).cssc().set_bit()
+               });
+    HSE_FAILED.store(true, Ordering::SeqCst);
+    true
+}
+
+/// Takes the HSE failure recorded by [`css_nmi_handler`]
+///
+/// [`css_nmi_handler`]: fn.css_nmi_handler.html
+pub fn take_hse_failure() -> Option<HseFailure> {
+    if HSE_FAILED.swap(false, Ordering::SeqCst) {
+        Some(HseFailure { _0: () })
+    } else {
+        None
+    }
+}
+
+const CIR_ENABLE_MASK: u32 = 0x0000_1F00;
+
+/// HSE failure detected by the clock security system
+pub struct HseFailure {
+    _0: (),
+}
+
+impl HseFailure {
+    /// Returns a fresh clock configuration to re-run planning on HSI
+    ///
+    /// HSE can't be used until the next reset, so the configuration must not
+    /// use it.
+    pub fn cfgr(self) -> CFGR {
+        CFGR::reset()
+    }
+}
+
+/// Flash wait states needed at `sysclk`
+pub(crate) fn latency_bits(sysclk: u32) -> u8 {
+    if sysclk <= 24_000_000 {
+        0b000
+    } else if sysclk <= 48_000_000 {
+        0b001
+    } else {
+        0b010
+    }
+}
+
+/// Busy waits until `ready` returns `true`, giving up after `timeout` polls
+pub(crate) fn wait_ready<F>(timeout: u32, ready: F) -> bool
+    where F: Fn() -> bool
+{
+    for _ in 0..timeout {
+        if ready() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Runs `f` with the backup domain write protection (PWR_CR DBP) lifted
+fn with_backup_domain<F, T>(f: F) -> Result<T, ClockError>
+    where F: FnOnce(&rcc::BDCR) -> T
+{
+    // NOTE(unsafe) PWREN and DBP are only touched here, DBP is back to its
+    // previous state before returning
+    let rcc = unsafe { &*RCC::ptr() };
+    let pwr = unsafe { &*PWR::ptr() };
+    rcc.apb1enr.modify(|_, w| w.pwren().enabled());
+    let protected = pwr.cr.read().dbp().bit_is_clear();
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+    let result = if wait_ready(DBP_TIMEOUT, || pwr.cr.read().dbp().bit_is_set())
+    {
+        Ok(f(&rcc.bdcr))
+    } else {
+        Err(ClockError::BackupDomainTimeout)
+    };
+
+    if protected {
+        pwr.cr.modify(|_, w| w.dbp().clear_bit());
+    }
+    result
+}
+
+/// Maps requested APB frequency to PPREx bits, `None` if it is higher than
+/// HCLK
+fn ppre_bits(hclk: u32, pclk: Option<u32>) -> Option<u8> {
+    match pclk {
+        Some(pclk) => match hclk / pclk {
+            0 => None,
+            1 => Some(0b011),
+            2 => Some(0b100),
+            3..=5 => Some(0b101),
+            6..=11 => Some(0b110),
+            _ => Some(0b111),
+        },
+        None => Some(0b011),
+    }
+}
+
+/// Searches PREDIV x PLLMUL combinations for the requested SYSCLK
+///
+/// Returns an exact match if there is one, otherwise reports the closest
+/// achievable frequency. With `usb` only PLL outputs USBPRE can turn into
+/// 48 MHz are considered.
+fn solve_pll(hse: Option<&HseConfig>,
+             sysclk: u32,
+             usb: bool)
+             -> Result<PllConfig, ClockError> {
+    let mut best: Option<PllConfig> = None;
+    let mut consider = |src: PllSrc, input: u32, prediv: u8| {
+        let pllin = input / u32(prediv);
+        if pllin < PLLIN_MIN || pllin > PLLIN_MAX {
+            return;
+        }
+        for pllmul in 2..=16u8 {
+            let freq = pllin * u32(pllmul);
+            if freq < PLLOUT_MIN || freq > PLLOUT_MAX {
+                continue;
+            }
+            // USB clock can only be derived from 48 or 72 MHz
+            if usb && freq != USB_PLL_DIV1 && freq != USB_PLL_DIV1_5 {
+                continue;
+            }
+            let better = match &best {
+                Some(b) => distance(freq, sysclk) < distance(b.freq, sysclk),
+                None => true,
+            };
+            if better {
+                best = Some(PllConfig { src,
+                                        prediv,
+                                        pllmul,
+                                        freq });
+            }
+        }
+    };
+
+    match hse {
+        Some(hse_cfg) => match hse_cfg.divider {
+            Some(divider) => {
+                consider(PllSrc::HseDivPrediv, hse_cfg.speed, divider as u8)
+            }
+            None => {
+                for prediv in 1..=16 {
+                    consider(PllSrc::HseDivPrediv, hse_cfg.speed, prediv);
+                }
+            }
+        },
+        None => {
+            // HSI is always divided by 2 on the legacy path
+            consider(PllSrc::HsiDiv2, HSI / 2, 1);
+            #[cfg(any(feature = "stm32f303xd", feature = "stm32f303xe"))]
+            for prediv in 1..=16 {
+                consider(PllSrc::HsiDivPrediv, HSI, prediv);
+            }
+        }
+    }
+
+    match best {
+        Some(pll) if pll.freq == sysclk => Ok(pll),
+        Some(pll) => Err(ClockError::SysclkInexact { closest: pll.freq }),
+        None => Err(ClockError::PllMulOutOfRange),
+    }
+}
+
+fn distance(a: u32, b: u32) -> u32 {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
+
+/// PLL input source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PllSrc {
+    /// HSI divided by 2
+    HsiDiv2,
+    /// HSI divided by PREDIV, only available on STM32F303xD/E
+    #[cfg(any(feature = "stm32f303xd", feature = "stm32f303xe"))]
+    HsiDivPrediv,
+    /// HSE divided by PREDIV
+    HseDivPrediv,
+}
+
+/// Solved PLL configuration
+#[derive(Clone, Copy, Debug)]
+struct PllConfig {
+    src: PllSrc,
+    prediv: u8,
+    pllmul: u8,
+    freq: u32,
+}
+
+/// Clock configuration error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockError {
+    /// Requested SYSCLK is higher than 72 MHz
+    SysclkUnreachable,
+    /// Requested HCLK is higher than generated SYSCLK
+    HclkUnreachable,
+    /// Requested PCLK1 is higher than generated HCLK
+    Pclk1Unreachable,
+    /// Resulting PCLK1 is higher than 36 MHz
+    Pclk1OverLimit,
+    /// Requested PCLK2 is higher than generated HCLK
+    Pclk2Unreachable,
+    /// HSE frequency is outside of the 4-32 MHz range (1-32 MHz in bypass
+    /// mode)
+    HseOutOfRange,
+    /// Requested SYSCLK can't be generated exactly by any PREDIV x PLLMUL
+    /// combination
+    SysclkInexact {
+        /// Closest achievable SYSCLK, in Hz
+        closest: u32,
+    },
+    /// No PREDIV x PLLMUL combination produces a valid PLL output
+    PllMulOutOfRange,
+    /// USB clock was required, but HSE is not configured
+    UsbRequiresHse,
+    /// Timer PLL x2 clock needs PLL as SYSCLK with AHB and APB2 undivided
+    PllX2Unavailable,
+    /// ADC PLL prescaler is set, but PLL is not running
+    AdcPllUnavailable,
+    /// HSE is required as a clock source, but not configured
+    HseNotEnabled,
+    /// LSE is required as a clock source, but not configured
+    LseNotEnabled,
+    /// LSI is required as a clock source, but not configured
+    LsiNotEnabled,
+    /// LSE in bypass mode is faster than 1 MHz
+    LseOutOfRange,
+    /// LSE didn't become ready in time, the crystal may be missing
+    LseTimeout,
+    /// LSI didn't become ready in time
+    LsiTimeout,
+    /// HSI didn't become ready in time
+    HsiTimeout,
+    /// HSE didn't become ready in time, the crystal may be missing
+    HseTimeout,
+    /// PLL didn't lock in time
+    PllTimeout,
+    /// SYSCLK didn't switch to the requested source in time
+    SwitchTimeout,
+    /// Another RTC clock source is already selected, only a backup domain
+    /// reset can change it
+    RtcSourceLocked,
+    /// Backup domain write protection (PWR_CR DBP) didn't lift in time
+    BackupDomainTimeout,
+}
+
+/// System clock source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SysClkSource {
+    /// High speed internal oscillator
+    Hsi,
+    /// High speed external oscillator
+    Hse,
+    /// Phase locked loop
+    Pll,
+}
+
+impl SysClkSource {
+    fn sw_bits(self) -> u8 {
+        match self {
+            SysClkSource::Hsi => 0b00,
+            SysClkSource::Hse => 0b01,
+            SysClkSource::Pll => 0b10,
+        }
+    }
+}
+
+/// LSE crystal drive capability (BDCR LSEDRV)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LseDrive {
+    /// Lowest drive (reset value)
+    Low,
+    /// Medium low drive
+    MediumLow,
+    /// Medium high drive
+    MediumHigh,
+    /// Highest drive
+    High,
+}
+
+impl LseDrive {
+    fn bits(self) -> u8 {
+        match self {
+            LseDrive::Low => 0b00,
+            LseDrive::MediumLow => 0b10,
+            LseDrive::MediumHigh => 0b01,
+            LseDrive::High => 0b11,
+        }
+    }
+}
+
+/// Low speed external oscillator configuration
+#[derive(Clone, Copy, Debug)]
+pub struct Lse {
+    speed: u32,
+    drive: LseDrive,
+    bypass: bool,
+}
+
+impl Lse {
+    /// 32.768 kHz crystal with the given drive capability
+    pub fn crystal(drive: LseDrive) -> Self {
+        Lse { speed: LSE,
+              drive,
+              bypass: false }
+    }
+
+    /// External clock of up to 1 MHz fed into OSC32_IN
+    pub fn bypass<F>(freq: F) -> Self
+        where F: Into<Hertz<u32>>
+    {
+        Lse { speed: freq.into().0,
+              drive: LseDrive::Low,
+              bypass: true }
+    }
+
+    /// Starts LSE unless it's already running, backup domain has to be
+    /// writable
+    fn enable(&self, bdcr: &rcc::BDCR) -> Result<(), ClockError> {
+        let r = bdcr.read();
+        if r.lseon().bit_is_set() && r.lserdy().bit_is_set() {
+            // kept running by VBAT across resets
+            return Ok(());
+        }
+
+        // LSEBYP can only be written while LSE is off
+        bdcr.modify(|_, w| w.lseon().clear_bit());
+        bdcr.modify(|_, w| unsafe {
+                w.lsedrv().bits(self.drive.bits()).lsebyp().bit(self.bypass)
+            });
+        bdcr.modify(|_, w| w.lseon().set_bit());
+
+        if wait_ready(LSE_READY_TIMEOUT, || bdcr.read().lserdy().bit_is_set()) {
+            Ok(())
+        } else {
+            Err(ClockError::LseTimeout)
+        }
+    }
+}
+
+/// Low speed internal oscillator, nominally 40 kHz
+#[derive(Clone, Copy, Debug)]
+pub struct Lsi;
+
+/// RTC clock source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtcClockSource {
+    /// Low speed external oscillator
+    Lse,
+    /// Low speed internal oscillator
+    Lsi,
+    /// High speed external oscillator divided by 32
+    HseDiv32,
+}
+
+impl RtcClockSource {
+    fn bits(self) -> u8 {
+        match self {
+            RtcClockSource::Lse => 0b01,
+            RtcClockSource::Lsi => 0b10,
+            RtcClockSource::HseDiv32 => 0b11,
+        }
+    }
+}
+
+/// USART kernel clock source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsartClockSource {
+    /// APB clock the USART is attached to (reset value)
+    Pclk,
+    /// System clock
+    Sysclk,
+    /// Low speed external oscillator, see [`CFGR::lse`]
+    ///
+    /// [`CFGR::lse`]: struct.CFGR.html#method.lse
+    Lse,
+    /// High speed internal oscillator
+    Hsi,
+}
+
+impl UsartClockSource {
+    fn bits(self) -> u8 {
+        match self {
+            UsartClockSource::Pclk => 0b00,
+            UsartClockSource::Sysclk => 0b01,
+            UsartClockSource::Lse => 0b10,
+            UsartClockSource::Hsi => 0b11,
+        }
+    }
+}
+
+/// I2C kernel clock source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum I2cClockSource {
+    /// High speed internal oscillator (reset value)
+    Hsi,
+    /// System clock
+    Sysclk,
+}
+
+/// Advanced-control timer kernel clock source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimClockSource {
+    /// APB2 timer clock (reset value)
+    Pclk,
+    /// PLL output x2, up to 144 MHz
+    PllX2,
+}
+
+/// ADC PLL clock prescaler
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdcPrescaler {
+    /// PLL clock to the ADC is off, ADC has to run from HCLK (reset value)
+    Off,
+    /// PLL / 1
+    Div1,
+    /// PLL / 2
+    Div2,
+    /// PLL / 4
+    Div4,
+    /// PLL / 6
+    Div6,
+    /// PLL / 8
+    Div8,
+    /// PLL / 10
+    Div10,
+    /// PLL / 12
+    Div12,
+    /// PLL / 16
+    Div16,
+    /// PLL / 32
+    Div32,
+    /// PLL / 64
+    Div64,
+    /// PLL / 128
+    Div128,
+    /// PLL / 256
+    Div256,
+}
+
+impl AdcPrescaler {
+    fn bits(self) -> u8 {
+        match self {
+            AdcPrescaler::Off => 0b00000,
+            AdcPrescaler::Div1 => 0b10000,
+            AdcPrescaler::Div2 => 0b10001,
+            AdcPrescaler::Div4 => 0b10010,
+            AdcPrescaler::Div6 => 0b10011,
+            AdcPrescaler::Div8 => 0b10100,
+            AdcPrescaler::Div10 => 0b10101,
+            AdcPrescaler::Div12 => 0b10110,
+            AdcPrescaler::Div16 => 0b10111,
+            AdcPrescaler::Div32 => 0b11000,
+            AdcPrescaler::Div64 => 0b11001,
+            AdcPrescaler::Div128 => 0b11010,
+            AdcPrescaler::Div256 => 0b11011,
+        }
+    }
+
+    fn divider(self) -> Option<u32> {
+        match self {
+            AdcPrescaler::Off => None,
+            AdcPrescaler::Div1 => Some(1),
+            AdcPrescaler::Div2 => Some(2),
+            AdcPrescaler::Div4 => Some(4),
+            AdcPrescaler::Div6 => Some(6),
+            AdcPrescaler::Div8 => Some(8),
+            AdcPrescaler::Div10 => Some(10),
+            AdcPrescaler::Div12 => Some(12),
+            AdcPrescaler::Div16 => Some(16),
+            AdcPrescaler::Div32 => Some(32),
+            AdcPrescaler::Div64 => Some(64),
+            AdcPrescaler::Div128 => Some(128),
+            AdcPrescaler::Div256 => Some(256),
+        }
+    }
+}
+
+/// Peripheral kernel clock selection, CFGR2 and CFGR3
+#[derive(Clone, Copy, Debug)]
+struct KernelClocks {
+    usart1: UsartClockSource,
+    usart2: UsartClockSource,
+    usart3: UsartClockSource,
+    i2c1: I2cClockSource,
+    i2c2: I2cClockSource,
+    tim1: TimClockSource,
+    #[cfg(feature = "stm32f303")]
+    tim8: TimClockSource,
+    adc12: AdcPrescaler,
+    #[cfg(feature = "stm32f303")]
+    adc34: AdcPrescaler,
+}
+
+impl KernelClocks {
+    fn reset() -> Self {
+        KernelClocks { usart1: UsartClockSource::Pclk,
+                       usart2: UsartClockSource::Pclk,
+                       usart3: UsartClockSource::Pclk,
+                       i2c1: I2cClockSource::Hsi,
+                       i2c2: I2cClockSource::Hsi,
+                       tim1: TimClockSource::Pclk,
+                       #[cfg(feature = "stm32f303")]
+                       tim8: TimClockSource::Pclk,
+                       adc12: AdcPrescaler::Off,
+                       #[cfg(feature = "stm32f303")]
+                       adc34: AdcPrescaler::Off }
+    }
+
+    fn uses_hsi(&self) -> bool {
+        self.usart1 == UsartClockSource::Hsi
+        || self.usart2 == UsartClockSource::Hsi
+        || self.usart3 == UsartClockSource::Hsi
+        || self.i2c1 == I2cClockSource::Hsi
+        || self.i2c2 == I2cClockSource::Hsi
+    }
+}
+
+/// Calculated clock configuration
+///
+/// Produced by [`CFGR::plan`](struct.CFGR.html#method.plan) before any
+/// register is touched.
+#[derive(Clone, Copy, Debug)]
+pub struct ClockPlan {
+    source: SysClkSource,
+    hpre_bits: u8,
+    ppre1_bits: u8,
+    ppre2_bits: u8,
+    latency_bits: u8,
+    usbpre_div1: bool,
+    kernel: KernelClocks,
+    clocks: Clocks,
+}
+
+impl ClockPlan {
+    /// Returns the system clock source
+    pub fn source(&self) -> SysClkSource {
+        self.source
+    }
+
+    /// Returns the flash wait states
+    pub fn latency(&self) -> u8 {
+        self.latency_bits
+    }
+
+    /// Returns the clock frequencies this plan results in
+    pub fn clocks(&self) -> Clocks {
+        self.clocks
     }
 }
 
 /// Frozen clock frequencies
 ///
-/// The existence of this value indicates that the clock configuration can no
-/// longer be changed
-#[derive(Clone, Copy)]
+/// Valid until the configuration is changed with
+/// [`CFGR::try_transition`](struct.CFGR.html#method.try_transition), drivers
+/// holding older clocks have to be notified through the returned
+/// [`ClockTransition`](struct.ClockTransition.html).
+#[derive(Clone, Copy, Debug)]
 pub struct Clocks {
     hclk: Hertz<u32>,
     pclk1: Hertz<u32>,
     pclk2: Hertz<u32>,
     ppre1: u8,
-    // TODO remove `allow`
-    #[allow(dead_code)]
     ppre2: u8,
+    pll: Option<PllConfig>,
     sysclk: Hertz<u32>,
+    usbclk: Option<Hertz<u32>>,
+    usart1clk: Hertz<u32>,
+    usart2clk: Hertz<u32>,
+    usart3clk: Hertz<u32>,
+    i2c1clk: Hertz<u32>,
+    i2c2clk: Hertz<u32>,
+    tim1clk: Hertz<u32>,
+    #[cfg(feature = "stm32f303")]
+    tim8clk: Hertz<u32>,
+    adc12clk: Option<Hertz<u32>>,
+    #[cfg(feature = "stm32f303")]
+    adc34clk: Option<Hertz<u32>>,
+    lse: Option<Hertz<u32>>,
+    lsi: Option<Hertz<u32>>,
+    rtcclk: Option<Hertz<u32>>,
 }
 
 impl Clocks {
+    /// Returns a fresh clock configuration to move away from these clocks
+    ///
+    /// Apply it with [`CFGR::try_transition`].
+    ///
+    /// [`CFGR::try_transition`]: struct.CFGR.html#method.try_transition
+    pub fn reconfigure(&self) -> CFGR {
+        CFGR::reset()
+    }
+
     /// Returns the frequency of the AHB
     pub fn hclk(&self) -> Hertz<u32> {
         self.hclk
@@ -440,18 +1452,366 @@ impl Clocks {
         self.pclk2
     }
 
-    pub(crate) fn ppre1(&self) -> u8 {
-        self.ppre1
+    /// Returns the clock frequency of the APB1 timers
+    ///
+    /// Timers run at twice the APB frequency unless the APB prescaler is 1.
+    pub fn timclk1(&self) -> Hertz<u32> {
+        if self.ppre1 == 1 {
+            self.pclk1
+        } else {
+            Hertz(self.pclk1.0 * 2)
+        }
     }
 
-    // TODO remove `allow`
-    #[allow(dead_code)]
-    pub(crate) fn ppre2(&self) -> u8 {
-        self.ppre2
+    /// Returns the clock frequency of the APB2 timers
+    ///
+    /// Timers run at twice the APB frequency unless the APB prescaler is 1.
+    /// TIM1 and TIM8 may be clocked from PLL x2 instead, see
+    /// [`tim1clk`](#method.tim1clk).
+    pub fn timclk2(&self) -> Hertz<u32> {
+        if self.ppre2 == 1 {
+            self.pclk2
+        } else {
+            Hertz(self.pclk2.0 * 2)
+        }
     }
 
     /// Returns the system (core) frequency
     pub fn sysclk(&self) -> Hertz<u32> {
         self.sysclk
     }
+
+    /// Returns the PLL input source, if PLL drives SYSCLK
+    pub fn pll_source(&self) -> Option<PllSrc> {
+        self.pll.map(|pll| pll.src)
+    }
+
+    /// Returns the PLL input divider, if PLL drives SYSCLK
+    pub fn prediv(&self) -> Option<u8> {
+        self.pll.map(|pll| pll.prediv)
+    }
+
+    /// Returns the PLL multiplier, if PLL drives SYSCLK
+    pub fn pllmul(&self) -> Option<u8> {
+        self.pll.map(|pll| pll.pllmul)
+    }
+
+    /// Returns the frequency of the USB clock
+    ///
+    /// `None` unless [`CFGR::require_usb_clock`] was used, USB must not be
+    /// started without it.
+    ///
+    /// [`CFGR::require_usb_clock`]: struct.CFGR.html#method.require_usb_clock
+    pub fn usbclk(&self) -> Option<Hertz<u32>> {
+        self.usbclk
+    }
+
+    /// Returns the USART1 kernel clock frequency
+    pub fn usart1clk(&self) -> Hertz<u32> {
+        self.usart1clk
+    }
+
+    /// Returns the USART2 kernel clock frequency
+    pub fn usart2clk(&self) -> Hertz<u32> {
+        self.usart2clk
+    }
+
+    /// Returns the USART3 kernel clock frequency
+    pub fn usart3clk(&self) -> Hertz<u32> {
+        self.usart3clk
+    }
+
+    /// Returns the I2C1 kernel clock frequency
+    pub fn i2c1clk(&self) -> Hertz<u32> {
+        self.i2c1clk
+    }
+
+    /// Returns the I2C2 kernel clock frequency
+    pub fn i2c2clk(&self) -> Hertz<u32> {
+        self.i2c2clk
+    }
+
+    /// Returns the TIM1 kernel clock frequency
+    pub fn tim1clk(&self) -> Hertz<u32> {
+        self.tim1clk
+    }
+
+    /// Returns the TIM8 kernel clock frequency
+    #[cfg(feature = "stm32f303")]
+    pub fn tim8clk(&self) -> Hertz<u32> {
+        self.tim8clk
+    }
+
+    /// Returns the ADC1/ADC2 PLL clock frequency, `None` if they run from HCLK
+    pub fn adc12clk(&self) -> Option<Hertz<u32>> {
+        self.adc12clk
+    }
+
+    /// Returns the ADC3/ADC4 PLL clock frequency, `None` if they run from HCLK
+    #[cfg(feature = "stm32f303")]
+    pub fn adc34clk(&self) -> Option<Hertz<u32>> {
+        self.adc34clk
+    }
+
+    /// Returns the LSE frequency, `None` if it wasn't enabled
+    pub fn lse(&self) -> Option<Hertz<u32>> {
+        self.lse
+    }
+
+    /// Returns the nominal LSI frequency, `None` if it wasn't enabled
+    pub fn lsi(&self) -> Option<Hertz<u32>> {
+        self.lsi
+    }
+
+    /// Returns the RTC clock frequency, `None` if no source was selected
+    pub fn rtcclk(&self) -> Option<Hertz<u32>> {
+        self.rtcclk
+    }
+}
+
+/// Microcontroller clock output source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum McoSource {
+    /// Low speed internal oscillator
+    Lsi,
+    /// Low speed external oscillator
+    Lse,
+    /// System clock
+    Sysclk,
+    /// High speed internal oscillator
+    Hsi,
+    /// High speed external oscillator
+    Hse,
+    /// PLL output divided by 2
+    PllDiv2,
+    /// PLL output, not divided
+    #[cfg(any(feature = "stm32f303xd", feature = "stm32f303xe"))]
+    Pll,
+}
+
+impl McoSource {
+    fn bits(self) -> u8 {
+        match self {
+            McoSource::Lsi => 0b010,
+            McoSource::Lse => 0b011,
+            McoSource::Sysclk => 0b100,
+            McoSource::Hsi => 0b101,
+            McoSource::Hse => 0b110,
+            McoSource::PllDiv2 => 0b111,
+            #[cfg(any(feature = "stm32f303xd", feature = "stm32f303xe"))]
+            McoSource::Pll => 0b111,
+        }
+    }
+}
+
+/// Microcontroller clock output prescaler
+#[cfg(any(feature = "stm32f303xd", feature = "stm32f303xe"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum McoPrescaler {
+    /// MCO / 1
+    Div1,
+    /// MCO / 2
+    Div2,
+    /// MCO / 4
+    Div4,
+    /// MCO / 8
+    Div8,
+    /// MCO / 16
+    Div16,
+    /// MCO / 32
+    Div32,
+    /// MCO / 64
+    Div64,
+    /// MCO / 128
+    Div128,
+}
+
+#[cfg(any(feature = "stm32f303xd", feature = "stm32f303xe"))]
+impl McoPrescaler {
+    fn bits(self) -> u8 {
+        match self {
+            McoPrescaler::Div1 => 0b000,
+            McoPrescaler::Div2 => 0b001,
+            McoPrescaler::Div4 => 0b010,
+            McoPrescaler::Div8 => 0b011,
+            McoPrescaler::Div16 => 0b100,
+            McoPrescaler::Div32 => 0b101,
+            McoPrescaler::Div64 => 0b110,
+            McoPrescaler::Div128 => 0b111,
+        }
+    }
+}
+
+/// Microcontroller clock output on PA8
+///
+/// The selected oscillator has to be running, otherwise the pin stays low.
+pub struct Mco<PT: PullType> {
+    pin: PA8<PT, AltFn<AF0, PushPull, HighSpeed>>,
+}
+
+impl<PT: PullType> Mco<PT> {
+    /// Routes `source` to PA8
+    pub fn new<PM: PinMode>(pin: PA8<PT, PM>, source: McoSource) -> Self {
+        let pin = pin.alternating(AF0).output_speed(HighSpeed);
+        let mut mco = Mco { pin };
+        mco.source(source);
+        mco
+    }
+
+    /// Changes the output source
+    pub fn source(&mut self, source: McoSource) {
+        // NOTE(unsafe) MCO bits are owned by this abstraction, the rest of
+        // CFGR is written back as it is
+        let rcc = unsafe { &*RCC::ptr() };
+        #[cfg(any(feature = "stm32f303xd", feature = "stm32f303xe"))]
+        rcc.cfgr.modify(|_, w| {
+                    w.pllnodiv().bit(source == McoSource::Pll)
+                });
+        rcc.cfgr.modify(|_, w| unsafe { w.mco().bits(source.bits()) });
+    }
+
+    /// Sets the output prescaler
+    #[cfg(any(feature = "stm32f303xd", feature = "stm32f303xe"))]
+    pub fn prescaler(&mut self, pre: McoPrescaler) {
+        // NOTE(unsafe) see `source`
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.cfgr.modify(|_, w| unsafe { w.mcopre().bits(pre.bits()) });
+    }
+
+    /// Stops the clock output and releases the pin
+    pub fn free(self) -> PA8<PT, AltFn<AF0, PushPull, HighSpeed>> {
+        // NOTE(unsafe) see `source`
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.cfgr.modify(|_, w| unsafe { w.mco().bits(0b000) });
+        self.pin
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MHZ: u32 = 1_000_000;
+
+    fn hse8() -> CFGR {
+        CFGR::reset().hse(Hertz(8 * MHZ),
+                          HseDivider::Auto,
+                          HseBypass::Disable)
+    }
+
+    #[test]
+    fn pll_exact_match() {
+        let plan = hse8().sysclk(Hertz(72 * MHZ))
+                         .pclk1(Hertz(36 * MHZ))
+                         .plan()
+                         .unwrap();
+        let clocks = plan.clocks();
+        assert_eq!(plan.source(), SysClkSource::Pll);
+        assert_eq!(clocks.sysclk().0, 72 * MHZ);
+        assert_eq!(clocks.pll_source(), Some(PllSrc::HseDivPrediv));
+        assert_eq!(clocks.prediv(), Some(1));
+        assert_eq!(clocks.pllmul(), Some(9));
+    }
+
+    #[test]
+    fn pll_inexact() {
+        let cfgr = CFGR::reset().hse(Hertz(8 * MHZ),
+                                     HseDivider::NoDivision,
+                                     HseBypass::Disable)
+                                .sysclk(Hertz(70 * MHZ))
+                                .pclk1(Hertz(35 * MHZ));
+        assert_eq!(cfgr.plan().unwrap_err(),
+                   ClockError::SysclkInexact { closest: 72 * MHZ });
+    }
+
+    #[test]
+    fn sysclk_over_limit() {
+        let cfgr = hse8().sysclk(Hertz(80 * MHZ));
+        assert_eq!(cfgr.plan().unwrap_err(), ClockError::SysclkUnreachable);
+    }
+
+    #[test]
+    fn usb_defaults_to_48mhz() {
+        let plan = hse8().require_usb_clock()
+                         .pclk1(Hertz(24 * MHZ))
+                         .plan()
+                         .unwrap();
+        let clocks = plan.clocks();
+        assert_eq!(clocks.sysclk().0, 48 * MHZ);
+        assert_eq!(clocks.usbclk().map(|f| f.0), Some(48 * MHZ));
+        assert!(plan.usbpre_div1);
+    }
+
+    #[test]
+    fn usb_at_72mhz() {
+        let plan = hse8().require_usb_clock()
+                         .sysclk(Hertz(72 * MHZ))
+                         .pclk1(Hertz(36 * MHZ))
+                         .plan()
+                         .unwrap();
+        assert_eq!(plan.clocks().usbclk().map(|f| f.0), Some(48 * MHZ));
+        assert!(!plan.usbpre_div1);
+    }
+
+    #[test]
+    fn usb_restricts_pll_output() {
+        let cfgr = hse8().require_usb_clock()
+                         .sysclk(Hertz(64 * MHZ))
+                         .pclk1(Hertz(32 * MHZ));
+        assert_eq!(cfgr.plan().unwrap_err(),
+                   ClockError::SysclkInexact { closest: 72 * MHZ });
+    }
+
+    #[test]
+    fn usb_requires_hse() {
+        let cfgr = CFGR::reset().require_usb_clock();
+        assert_eq!(cfgr.plan().unwrap_err(), ClockError::UsbRequiresHse);
+    }
+
+    #[test]
+    fn reset_runs_from_hsi() {
+        let plan = CFGR::reset().plan().unwrap();
+        assert_eq!(plan.source(), SysClkSource::Hsi);
+        assert_eq!(plan.latency(), 0b000);
+        assert_eq!(plan.clocks().sysclk().0, 8 * MHZ);
+        assert_eq!(plan.clocks().pclk1().0, 8 * MHZ);
+    }
+
+    #[test]
+    fn prescalers() {
+        let plan = hse8().sysclk(Hertz(72 * MHZ))
+                         .hclk(Hertz(36 * MHZ))
+                         .pclk1(Hertz(9 * MHZ))
+                         .pclk2(Hertz(18 * MHZ))
+                         .plan()
+                         .unwrap();
+        let clocks = plan.clocks();
+        assert_eq!(plan.hpre_bits, 0b1000);
+        assert_eq!(plan.ppre1_bits, 0b101);
+        assert_eq!(plan.ppre2_bits, 0b100);
+        assert_eq!(clocks.hclk().0, 36 * MHZ);
+        assert_eq!(clocks.pclk1().0, 9 * MHZ);
+        assert_eq!(clocks.pclk2().0, 18 * MHZ);
+        assert_eq!(clocks.timclk1().0, 18 * MHZ);
+        assert_eq!(clocks.timclk2().0, 36 * MHZ);
+    }
+
+    #[test]
+    fn pclk1_over_limit() {
+        let cfgr = hse8().sysclk(Hertz(72 * MHZ));
+        assert_eq!(cfgr.plan().unwrap_err(), ClockError::Pclk1OverLimit);
+    }
+
+    #[test]
+    fn flash_latency() {
+        assert_eq!(latency_bits(24 * MHZ), 0b000);
+        assert_eq!(latency_bits(48 * MHZ), 0b001);
+        assert_eq!(latency_bits(72 * MHZ), 0b010);
+
+        let plan = hse8().sysclk(Hertz(48 * MHZ))
+                         .pclk1(Hertz(24 * MHZ))
+                         .plan()
+                         .unwrap();
+        assert_eq!(plan.latency(), 0b001);
+    }
 }
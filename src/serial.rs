@@ -13,10 +13,13 @@ use crate::dma::{dma1, CircBuffer, Static, Transfer, R, W};
 use crate::gpio::{AltFn, HighSpeed, PinMode, PullType, PushPull, AF7};
 use crate::gpio::{PA10, PA14, PA15, PA2, PA3, PA9};
 use crate::gpio::{PB10, PB11, PB3, PB4, PB6, PB7};
+#[cfg(not(feature = "lqfp48"))]
 use crate::gpio::{PC10, PC11, PC4, PC5};
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
 use crate::gpio::{PD5, PD6, PD8, PD9};
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
 use crate::gpio::{PE0, PE1, PE15};
-use crate::rcc::Clocks;
+use crate::rcc::{self, Clocks, Reclock};
 use crate::time::Bps;
 
 /// Interrupt event
@@ -42,10 +45,23 @@ pub enum Error {
     _Extensible,
 }
 
+/// Error reported when the USART can't follow a clock change
+#[derive(Debug)]
+pub enum ReclockError {
+    /// The baud rate is higher than the new kernel clock / 16
+    BaudRate,
+    /// The last frame didn't finish transmitting
+    Timeout,
+}
+
+// TC polls, enough for a frame at 300 bps on a 72 MHz core
+const TC_TIMEOUT: u32 = 1_000_000;
+
 /// Serial abstraction
 pub struct Serial<USART, PINS> {
     usart: USART,
     pins: PINS,
+    baud_rate: Bps<u32>,
 }
 
 /// Serial receiver
@@ -73,99 +89,90 @@ pub trait SerialExt<USART, ITX, IRX, TX, RX> {
               -> Serial<USART, (TX, RX)>;
 }
 
-macro_rules! serial {
-    ($USARTX:ident,
-     $INTNAME:ident,
-     $apbenr:ident,
-     $apbrstr:ident,
-     $usartXen:ident,
-     $usartXrst:ident,
-     $pclkX:ident,
-     $afn:ident,
-     $speed:ident,
-     [$($txpin: ident, )+],
-     $restrx: tt
-    ) => {
-        serial!{$USARTX,
-                $INTNAME,
-                $apbenr,
-                $apbrstr,
-                $usartXen,
-                $usartXrst,
-                $pclkX,
-                $afn,
-                $speed,
-                [$(
-                    ($txpin, $restrx),
-                )+]}
+/// TX pin of `USART`
+pub trait TxPin<USART> {
+    /// Pin switched to the alternate function
+    type Output;
+    /// Switches the pin to the alternate function of `USART`
+    fn setup(self) -> Self::Output;
+}
+
+/// RX pin of `USART`
+pub trait RxPin<USART> {
+    /// Pin switched to the alternate function
+    type Output;
+    /// Switches the pin to the alternate function of `USART`
+    fn setup(self) -> Self::Output;
+}
+
+macro_rules! pins {
+    ($($(#[$attr:meta])*
+       $PIN:ident: ($Pin:ident, $USARTX:ident, $afn:ident),)+) => {
+        $(
+            $(#[$attr])*
+            impl<PT: PullType, PM: PinMode> $Pin<$USARTX> for $PIN<PT, PM> {
+                // XXX: not sure, maybe we should allow setting the speed
+                type Output = $PIN<PT, AltFn<$afn, PushPull, HighSpeed>>;
+
+                fn setup(self) -> Self::Output {
+                    self.alternating($afn).output_speed(HighSpeed)
+                }
+            }
+        )+
     };
+}
+
+macro_rules! serial {
     ($USARTX:ident,
      $INTNAME:ident,
      $apbenr:ident,
      $apbrstr:ident,
      $usartXen:ident,
      $usartXrst:ident,
-     $pclkX:ident,
-     $afn:ident,
-     $speed:ident,
-     [$(($txpin: ident, [$($rxpin: ident,)+]), )+]
+     $clkX:ident
     ) => {
-        $(
-            $(
-                impl <PT: PullType,
-                PM: PinMode>
-                    SerialExt<$USARTX,
-                $txpin<PT, PM>,
-                $rxpin<PT, PM>,
-                $txpin<PT, AltFn<$afn, PushPull, $speed>>,
-                $rxpin<PT, AltFn<$afn, PushPull, $speed>>>
-                    for $USARTX {
-                        fn serial(self,
-                                  pins: ($txpin<PT, PM>, $rxpin<PT, PM>),
-                                  baud_rate: Bps<u32>,
-                                  clocks: Clocks)
-                                  -> Serial<$USARTX, ($txpin<PT, AltFn<$afn, PushPull, $speed>>,
-                                                      $rxpin<PT, AltFn<$afn, PushPull, $speed>>)>
-                        {
-                            let outpins = (
-                                pins.0
-                                    .alternating($afn)
-                                    .output_speed($speed),
-                                pins.1
-                                    .alternating($afn)
-                                    .output_speed($speed),
-                            );
-
-                            // enable or reset $USARTX
-                            let apbenr = unsafe { &(*RCC::ptr()).$apbenr };
-                            let apbrstr = unsafe { &(*RCC::ptr()).$apbrstr };
-                            apbenr.modify(|_, w| w.$usartXen().enabled());
-                            apbrstr.modify(|_, w| w.$usartXrst().set_bit());
-                            apbrstr.modify(|_, w| w.$usartXrst().clear_bit());
-                            self.cr3.write(|w| w.dmat().set_bit().dmar().set_bit());
-
-                            let brr = clocks.$pclkX().0 / baud_rate.0;
-                            assert!(brr >= 16, "impossible baud rate");
-                            self.brr.write(|w| unsafe { w.bits(brr) });
-
-                            // UE: enable USART
-                            // RE: enable receiver
-                            // TE: enable transceiver
-                            self.cr1.write(|w| {
-                                w.ue()
-                                    .set_bit()
-                                    .re()
-                                    .set_bit()
-                                    .te()
-                                    .set_bit()
-                            });
-
-                            Serial { usart: self,
-                                     pins: outpins, }
-                        }
-                    }
-            )+
-        )+
+        impl<ITX, IRX> SerialExt<$USARTX, ITX, IRX, ITX::Output, IRX::Output>
+            for $USARTX
+            where ITX: TxPin<$USARTX>,
+                  IRX: RxPin<$USARTX>
+        {
+            fn serial(self,
+                      pins: (ITX, IRX),
+                      baud_rate: Bps<u32>,
+                      clocks: Clocks)
+                      -> Serial<$USARTX, (ITX::Output, IRX::Output)>
+            {
+                let outpins = (pins.0.setup(), pins.1.setup());
+
+                // enable or reset $USARTX
+                let apbenr = unsafe { &(*RCC::ptr()).$apbenr };
+                let apbrstr = unsafe { &(*RCC::ptr()).$apbrstr };
+                apbenr.modify(|_, w| w.$usartXen().enabled());
+                apbrstr.modify(|_, w| w.$usartXrst().set_bit());
+                apbrstr.modify(|_, w| w.$usartXrst().clear_bit());
+                self.cr3.write(|w| w.dmat().set_bit().dmar().set_bit());
+
+                let brr = clocks.$clkX().0 / baud_rate.0;
+                assert!(brr >= 16, "impossible baud rate");
+                self.brr.write(|w| unsafe { w.bits(brr) });
+
+                // UE: enable USART
+                // RE: enable receiver
+                // TE: enable transceiver
+                self.cr1.write(|w| {
+                    w.ue()
+                        .set_bit()
+                        .re()
+                        .set_bit()
+                        .te()
+                        .set_bit()
+                });
+
+                Serial { usart: self,
+                         pins: outpins,
+                         baud_rate, }
+            }
+        }
 
         impl<TX, RX> Serial<$USARTX, (TX, RX)> {
             /// Returns associated interrupt
@@ -209,6 +216,29 @@ macro_rules! serial {
             }
         }
 
+        impl<TX, RX> Reclock for Serial<$USARTX, (TX, RX)> {
+            type Error = ReclockError;
+
+            fn reclock(&mut self, clocks: Clocks) -> Result<(), Self::Error> {
+                let brr = clocks.$clkX().0 / self.baud_rate.0;
+                if brr < 16 {
+                    return Err(ReclockError::BaudRate);
+                }
+
+                // let the last frame go out, BRR is only writable while UE=0
+                let usart = &self.usart;
+                if !rcc::wait_ready(TC_TIMEOUT, || {
+                    usart.isr.read().tc().bit_is_set()
+                }) {
+                    return Err(ReclockError::Timeout);
+                }
+                self.usart.cr1.modify(|_, w| w.ue().clear_bit());
+                self.usart.brr.write(|w| unsafe { w.bits(brr) });
+                self.usart.cr1.modify(|_, w| w.ue().set_bit());
+                Ok(())
+            }
+        }
+
         impl Rx<$USARTX> {
             /// clear overrun
             pub fn clear_overrun_error(&mut self) -> u8 {
@@ -530,37 +560,62 @@ pub trait WriteDma<A, B>: DmaChannel
                  -> Transfer<R, B, Self::Dma, Self>;
 }
 
-// XXX: we can't use GATs yet, so had to retort to macros
+// alternate functions from the datasheet
+pins! {
+    PA9: (TxPin, USART1, AF7),
+    PB6: (TxPin, USART1, AF7),
+    #[cfg(not(feature = "lqfp48"))]
+    PC4: (TxPin, USART1, AF7),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+    PE0: (TxPin, USART1, AF7),
+    PA10: (RxPin, USART1, AF7),
+    PB7: (RxPin, USART1, AF7),
+    #[cfg(not(feature = "lqfp48"))]
+    PC5: (RxPin, USART1, AF7),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+    PE1: (RxPin, USART1, AF7),
+    PA2: (TxPin, USART2, AF7),
+    PA14: (TxPin, USART2, AF7),
+    PB3: (TxPin, USART2, AF7),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+    PD5: (TxPin, USART2, AF7),
+    PA3: (RxPin, USART2, AF7),
+    PA15: (RxPin, USART2, AF7),
+    PB4: (RxPin, USART2, AF7),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+    PD6: (RxPin, USART2, AF7),
+    PB10: (TxPin, USART3, AF7),
+    #[cfg(not(feature = "lqfp48"))]
+    PC10: (TxPin, USART3, AF7),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+    PD8: (TxPin, USART3, AF7),
+    PB11: (RxPin, USART3, AF7),
+    #[cfg(not(feature = "lqfp48"))]
+    PC11: (RxPin, USART3, AF7),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+    PD9: (RxPin, USART3, AF7),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+    PE15: (RxPin, USART3, AF7),
+}
+
 serial!(USART1,
         USART1_EXTI25,
         apb2enr,
         apb2rstr,
         usart1en,
         usart1rst,
-        pclk2,
-        AF7,
-        HighSpeed, // XXX: not sure, maybe we should allow setting this
-        [PA9, PB6, PC4, PE0,],
-        [PA10, PB7, PC5, PE1,]);
+        usart1clk);
 serial!(USART2,
         USART2_EXTI26,
         apb1enr,
         apb1rstr,
         usart2en,
         usart2rst,
-        pclk1,
-        AF7,
-        HighSpeed,
-        [PA2, PA14, PB3, PD5,],
-        [PA3, PA15, PB4, PD6,]);
+        usart2clk);
 serial!(USART3,
         USART3_EXTI28,
         apb1enr,
         apb1rstr,
         usart3en,
         usart3rst,
-        pclk1,
-        AF7,
-        HighSpeed,
-        [PB10, PC10, PD8,],
-        [PB11, PC11, PD9, PE15,]);
+        usart3clk);
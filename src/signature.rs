@@ -0,0 +1,170 @@
+//! Device electronic signature
+//!
+//! The unique device ID and the flash size are factory programmed into
+//! system memory, the device and revision come from the DBGMCU IDCODE
+//! register. Unlike later families the F30x has no package data register.
+
+use core::ptr;
+use core::str;
+
+/// Unique device ID register
+const UID: *const u8 = 0x1FFF_F7AC as *const u8;
+/// Flash size register, in KiB
+const FLASH_SIZE: *const u16 = 0x1FFF_F7CC as *const u16;
+/// DBGMCU_IDCODE register
+const IDCODE: *const u32 = 0xE004_2000 as *const u32;
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+/// 96-bit unique device ID
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Uid {
+    bytes: [u8; 12],
+}
+
+impl Uid {
+    /// Reads the unique device ID
+    pub fn read() -> Self {
+        let mut bytes = [0; 12];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            // NOTE(unsafe) read only system memory
+            *byte = unsafe { ptr::read_volatile(UID.add(i)) };
+        }
+        Uid { bytes }
+    }
+
+    /// Returns the ID as bytes in memory order
+    pub fn bytes(&self) -> [u8; 12] {
+        self.bytes
+    }
+
+    /// Returns the ID as the three words UID[31:0], UID[63:32], UID[95:64]
+    pub fn words(&self) -> [u32; 3] {
+        let word = |i: usize| {
+            u32::from(self.bytes[i])
+            | u32::from(self.bytes[i + 1]) << 8
+            | u32::from(self.bytes[i + 2]) << 16
+            | u32::from(self.bytes[i + 3]) << 24
+        };
+        [word(0), word(4), word(8)]
+    }
+
+    /// Returns the X coordinate on the wafer
+    pub fn x(&self) -> u16 {
+        u16::from(self.bytes[0]) | u16::from(self.bytes[1]) << 8
+    }
+
+    /// Returns the Y coordinate on the wafer
+    pub fn y(&self) -> u16 {
+        u16::from(self.bytes[2]) | u16::from(self.bytes[3]) << 8
+    }
+
+    /// Returns the wafer number
+    pub fn wafer(&self) -> u8 {
+        self.bytes[4]
+    }
+
+    /// Returns the lot number, ASCII encoded
+    pub fn lot(&self) -> [u8; 7] {
+        let mut lot = [0; 7];
+        lot.copy_from_slice(&self.bytes[5..12]);
+        lot
+    }
+
+    /// Formats the ID as 24 upper case hex digits into `buf`, most
+    /// significant byte first, e.g. for USB serial number strings
+    pub fn to_hex<'a>(&self, buf: &'a mut [u8; 24]) -> &'a str {
+        for (i, byte) in self.bytes.iter().rev().enumerate() {
+            buf[2 * i] = HEX[usize::from(byte >> 4)];
+            buf[2 * i + 1] = HEX[usize::from(byte & 0xF)];
+        }
+        // NOTE(unsafe) only ASCII hex digits were written
+        unsafe { str::from_utf8_unchecked(&buf[..]) }
+    }
+}
+
+/// Returns the size of the flash memory in KiB
+pub fn flash_size_kib() -> u16 {
+    // NOTE(unsafe) read only system memory
+    unsafe { ptr::read_volatile(FLASH_SIZE) }
+}
+
+/// Device line, the DEV_ID field of DBGMCU IDCODE
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceId {
+    /// STM32F302xB/C and STM32F303xB/C (0x422)
+    F30xBC,
+    /// STM32F303x6/8 and STM32F328 (0x438)
+    F303x68,
+    /// STM32F301x6/8, STM32F302x6/8 and STM32F318 (0x439)
+    F30xx68,
+    /// STM32F302xD/E, STM32F303xD/E and STM32F398 (0x446)
+    F30xDE,
+    /// Any other DEV_ID
+    Unknown(u16),
+}
+
+impl DeviceId {
+    fn from_bits(bits: u16) -> Self {
+        match bits {
+            0x422 => DeviceId::F30xBC,
+            0x438 => DeviceId::F303x68,
+            0x439 => DeviceId::F30xx68,
+            0x446 => DeviceId::F30xDE,
+            _ => DeviceId::Unknown(bits),
+        }
+    }
+}
+
+/// Silicon revision, the REV_ID field of DBGMCU IDCODE
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceRevision {
+    /// Revision A (0x1000)
+    A,
+    /// Revision Z (0x1001)
+    Z,
+    /// Revision Y (0x1003)
+    Y,
+    /// Any other REV_ID
+    Unknown(u16),
+}
+
+impl DeviceRevision {
+    fn from_bits(bits: u16) -> Self {
+        match bits {
+            0x1000 => DeviceRevision::A,
+            0x1001 => DeviceRevision::Z,
+            0x1003 => DeviceRevision::Y,
+            _ => DeviceRevision::Unknown(bits),
+        }
+    }
+}
+
+/// Contents of the DBGMCU IDCODE register
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IdCode {
+    bits: u32,
+}
+
+impl IdCode {
+    /// Reads the DBGMCU IDCODE register
+    pub fn read() -> Self {
+        // NOTE(unsafe) atomic read with no side effects
+        IdCode { bits: unsafe { ptr::read_volatile(IDCODE) } }
+    }
+
+    /// Returns the raw register value
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Returns the device line
+    pub fn device(&self) -> DeviceId {
+        DeviceId::from_bits((self.bits & 0xFFF) as u16)
+    }
+
+    /// Returns the silicon revision
+    pub fn revision(&self) -> DeviceRevision {
+        DeviceRevision::from_bits((self.bits >> 16) as u16)
+    }
+}
@@ -5,13 +5,17 @@ use core::ptr;
 use crate::pac::{RCC, SPI1, SPI2, SPI3};
 use hal::spi::{FullDuplex, Mode, Phase, Polarity};
 use nb;
+use void::Void;
 
 use crate::gpio::{AltFn, PullType, AF5, AF6};
 use crate::gpio::{HighSpeed, PinMode, PushPull};
-use crate::gpio::{PA5, PA6, PA7};
+use crate::gpio::{PA10, PA11, PA5, PA6, PA7};
 use crate::gpio::{PB13, PB14, PB15, PB3, PB4, PB5};
+#[cfg(not(feature = "lqfp48"))]
 use crate::gpio::{PC10, PC11, PC12};
-use crate::rcc::Clocks;
+#[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+use crate::gpio::{PF10, PF9};
+use crate::rcc::{Clocks, Reclock};
 use crate::time::Hertz;
 
 /// SPI error
@@ -31,6 +35,7 @@ pub enum Error {
 pub struct Spi<SPI, PINS> {
     spi: SPI,
     pins: PINS,
+    freq: Hertz<u32>,
 }
 
 /// SPI extension for SPI
@@ -49,6 +54,62 @@ pub trait SpiExt<SPI, ISCK, IMISO, IMOSI, SCK, MISO, MOSI> {
         where F: Into<Hertz<u32>>;
 }
 
+/// Baud rate control bits for the highest SCK not above `freq`
+///
+/// Saturates to PCLK / 2 if `freq` is higher than that.
+fn br_bits(pclk: u32, freq: u32) -> u8 {
+    match pclk / freq {
+        0..=2 => 0b000,
+        3..=5 => 0b001,
+        6..=11 => 0b010,
+        12..=23 => 0b011,
+        24..=39 => 0b100,
+        40..=95 => 0b101,
+        96..=191 => 0b110,
+        _ => 0b111,
+    }
+}
+
+/// SCK pin of `SPI`
+pub trait SckPin<SPI> {
+    /// Pin switched to the alternate function
+    type Output;
+    /// Switches the pin to the alternate function of `SPI`
+    fn setup(self) -> Self::Output;
+}
+
+/// MISO pin of `SPI`
+pub trait MisoPin<SPI> {
+    /// Pin switched to the alternate function
+    type Output;
+    /// Switches the pin to the alternate function of `SPI`
+    fn setup(self) -> Self::Output;
+}
+
+/// MOSI pin of `SPI`
+pub trait MosiPin<SPI> {
+    /// Pin switched to the alternate function
+    type Output;
+    /// Switches the pin to the alternate function of `SPI`
+    fn setup(self) -> Self::Output;
+}
+
+macro_rules! pins {
+    ($($(#[$attr:meta])*
+       $PIN:ident: ($Pin:ident, $SPIX:ident, $afn:ident),)+) => {
+        $(
+            $(#[$attr])*
+            impl<PT: PullType, PM: PinMode> $Pin<$SPIX> for $PIN<PT, PM> {
+                type Output = $PIN<PT, AltFn<$afn, PushPull, HighSpeed>>;
+
+                fn setup(self) -> Self::Output {
+                    self.alternating($afn).output_speed(HighSpeed)
+                }
+            }
+        )+
+    };
+}
+
 #[cfg_attr(rustfmt, rustfmt_skip)]
 macro_rules! spi {
     ($SPIX:ident,
@@ -56,170 +117,92 @@ macro_rules! spi {
      $apbrstr:ident,
      $spiXen:ident,
      $spiXrst:ident,
-     $pclkX:ident,
-     $afn:ident,
-     $speed:ident,
-     sck: [$($sck: ident, )+],
-     miso: $miso: tt,
-     mosi: $mosi: tt
+     $pclkX:ident
     ) => {
-        spi!{
-            $SPIX,
-            $apbenr,
-            $apbrstr,
-            $spiXen,
-            $spiXrst,
-            $pclkX,
-            $afn,
-            $speed,
-            [$(
-                ($sck, $miso, ),
-            )+],
-            $mosi
-        }
-    };
-    ($SPIX:ident,
-     $apbenr:ident,
-     $apbrstr:ident,
-     $spiXen:ident,
-     $spiXrst:ident,
-     $pclkX:ident,
-     $afn:ident,
-     $speed:ident,
-     [$(($sck: ident,
-         [$($miso: ident, )+], ),
-     )+],
-     $mosi: tt
-    ) => {
-        spi!{
-            $SPIX,
-            $apbenr,
-            $apbrstr,
-            $spiXen,
-            $spiXrst,
-            $pclkX,
-            $afn,
-            $speed,
-            [$(
-                ($sck,
-                 [$(
-                     ($miso, $mosi),
-                 )+]),
-            )+]
-        }
-    };
-    ($SPIX:ident,
-     $apbenr:ident,
-     $apbrstr:ident,
-     $spiXen:ident,
-     $spiXrst:ident,
-     $pclkX:ident,
-     $afn:ident,
-     $speed:ident,
-     [$(($sck: ident,
-         [$(($miso: ident, [$($mosi: ident,)+]), )+]
-     ), )+]
-    ) => {
-        $(
-            $(
-                $(
-                    impl<PT: PullType, PM: PinMode>
-                        SpiExt<$SPIX,
-                    $sck<PT, PM>,
-                    $miso<PT, PM>,
-                    $mosi<PT, PM>,
-                    $sck<PT, AltFn<$afn, PushPull, $speed>>,
-                    $miso<PT, AltFn<$afn, PushPull, $speed>>,
-                    $mosi<PT, AltFn<$afn, PushPull, $speed>>> for $SPIX
-                    {
-                        fn spi<F>(
-                            self,
-                            pins: ($sck<PT, PM>, $miso<PT, PM>, $mosi<PT, PM>),
-                            mode: Mode,
-                            freq: F,
-                            clocks: Clocks)
-                            -> Spi<$SPIX,
-                        ($sck<PT, AltFn<$afn, PushPull, $speed>>,
-                         $miso<PT, AltFn<$afn, PushPull, $speed>>,
-                         $mosi<PT, AltFn<$afn, PushPull, $speed>>)>
-                        where F: Into<Hertz<u32>>
-                        {
-                            let outpins = (pins.0.alternating($afn).output_speed($speed),
-                                           pins.1.alternating($afn).output_speed($speed),
-                                           pins.2.alternating($afn).output_speed($speed));
-                            let apbenr = unsafe { &(*RCC::ptr()).$apbenr };
-                            let apbrstr = unsafe { &(*RCC::ptr()).$apbrstr };
-                            // enable or reset $SPIX
-                            apbenr.modify(|_, w| w.$spiXen().enabled());
-                            apbrstr.modify(|_, w| w.$spiXrst().set_bit());
-                            apbrstr.modify(|_, w| w.$spiXrst().clear_bit());
+        impl<ISCK, IMISO, IMOSI>
+            SpiExt<$SPIX,
+                   ISCK,
+                   IMISO,
+                   IMOSI,
+                   ISCK::Output,
+                   IMISO::Output,
+                   IMOSI::Output> for $SPIX
+            where ISCK: SckPin<$SPIX>,
+                  IMISO: MisoPin<$SPIX>,
+                  IMOSI: MosiPin<$SPIX>
+        {
+            fn spi<F>(
+                self,
+                pins: (ISCK, IMISO, IMOSI),
+                mode: Mode,
+                freq: F,
+                clocks: Clocks)
+                -> Spi<$SPIX, (ISCK::Output, IMISO::Output, IMOSI::Output)>
+            where F: Into<Hertz<u32>>
+            {
+                let outpins = (pins.0.setup(), pins.1.setup(), pins.2.setup());
+                let apbenr = unsafe { &(*RCC::ptr()).$apbenr };
+                let apbrstr = unsafe { &(*RCC::ptr()).$apbrstr };
+                // enable or reset $SPIX
+                apbenr.modify(|_, w| w.$spiXen().enabled());
+                apbrstr.modify(|_, w| w.$spiXrst().set_bit());
+                apbrstr.modify(|_, w| w.$spiXrst().clear_bit());
 
-                            // FRXTH: RXNE event is generated if the FIFO level is greater
-                            // than or equal to        8-bit
-                            // DS: 8-bit data size
-                            // SSOE: Slave Select output disabled
-                            self.cr2.write(|w| unsafe {
-                                w.frxth()
-                                    .set_bit()
-                                    .ds()
-                                    .bits(0b111)
-                                    .ssoe()
-                                    .clear_bit()
-                            });
+                // FRXTH: RXNE event is generated if the FIFO level is greater
+                // than or equal to        8-bit
+                // DS: 8-bit data size
+                // SSOE: Slave Select output disabled
+                self.cr2.write(|w| unsafe {
+                    w.frxth()
+                        .set_bit()
+                        .ds()
+                        .bits(0b111)
+                        .ssoe()
+                        .clear_bit()
+                });
 
-                            let br = match clocks.$pclkX().0 / freq.into().0 {
-                                0 => unreachable!(),
-                                1..=2 => 0b000,
-                                3..=5 => 0b001,
-                                6..=11 => 0b010,
-                                12..=23 => 0b011,
-                                24..=39 => 0b100,
-                                40..=95 => 0b101,
-                                96..=191 => 0b110,
-                                _ => 0b111,
-                            };
+                let freq = freq.into();
+                let br = br_bits(clocks.$pclkX().0, freq.0);
 
-                            // CPHA: phase
-                            // CPOL: polarity
-                            // MSTR: master mode
-                            // BR: 1 MHz
-                            // SPE: SPI disabled
-                            // LSBFIRST: MSB first
-                            // SSM: enable software slave management (NSS pin free for
-                            // other uses) SSI: set nss high = master mode
-                            // CRCEN: hardware CRC calculation disabled
-                            // BIDIMODE: 2 line unidirectional (full duplex)
-                            self.cr1.write(|w|
-                                w.cpha()
-                                    .bit(mode.phase
-                                         == Phase::CaptureOnSecondTransition)
-                                    .cpol()
-                                    .bit(mode.polarity == Polarity::IdleHigh)
-                                    .mstr()
-                                    .set_bit()
-                                    .br()
-                                    .bits(br)
-                                    .spe()
-                                    .set_bit()
-                                    .lsbfirst()
-                                    .clear_bit()
-                                    .ssi()
-                                    .set_bit()
-                                    .ssm()
-                                    .set_bit()
-                                    .crcen()
-                                    .clear_bit()
-                                    .bidimode()
-                                    .clear_bit()
-                            );
+                // CPHA: phase
+                // CPOL: polarity
+                // MSTR: master mode
+                // BR: 1 MHz
+                // SPE: SPI disabled
+                // LSBFIRST: MSB first
+                // SSM: enable software slave management (NSS pin free for
+                // other uses) SSI: set nss high = master mode
+                // CRCEN: hardware CRC calculation disabled
+                // BIDIMODE: 2 line unidirectional (full duplex)
+                self.cr1.write(|w|
+                    w.cpha()
+                        .bit(mode.phase
+                             == Phase::CaptureOnSecondTransition)
+                        .cpol()
+                        .bit(mode.polarity == Polarity::IdleHigh)
+                        .mstr()
+                        .set_bit()
+                        .br()
+                        .bits(br)
+                        .spe()
+                        .set_bit()
+                        .lsbfirst()
+                        .clear_bit()
+                        .ssi()
+                        .set_bit()
+                        .ssm()
+                        .set_bit()
+                        .crcen()
+                        .clear_bit()
+                        .bidimode()
+                        .clear_bit()
+                );
 
-                            Spi { spi: self,
-                                  pins: outpins, }
-                        }
-                    }
-                )+
-            )+
-        )+
+                Spi { spi: self,
+                      pins: outpins,
+                      freq, }
+            }
+        }
 
         impl<SCK, MISO, MOSI> Spi<$SPIX, (SCK, MISO, MOSI)> {
             /// Releases the SPI peripheral and associated pins
@@ -228,6 +211,21 @@ macro_rules! spi {
             }
         }
 
+        impl<SCK, MISO, MOSI> Reclock for Spi<$SPIX, (SCK, MISO, MOSI)> {
+            type Error = Void;
+
+            fn reclock(&mut self, clocks: Clocks) -> Result<(), Self::Error> {
+                let br = br_bits(clocks.$pclkX().0, self.freq.0);
+
+                // BR must not be changed while a transfer is ongoing
+                while self.spi.sr.read().bsy().bit_is_set() {}
+                self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+                self.spi.cr1.modify(|_, w| w.br().bits(br));
+                self.spi.cr1.modify(|_, w| w.spe().set_bit());
+                Ok(())
+            }
+        }
+
         impl<PINS> FullDuplex<u8> for Spi<$SPIX, PINS> {
             type Error = Error;
 
@@ -284,39 +282,34 @@ macro_rules! spi {
     };
 }
 
-//// DEFINE traits for Spi1Sck, Spi1Miso or, even better Sck<dev = SPI1>
-
-spi!(SPI1,
-     apb2enr,
-     apb2rstr,
-     spi1en,
-     spi1rst,
-     pclk2,
-     AF5,
-     HighSpeed,
-     sck: [PA5, PB3,],
-     miso: [PA6, PB4,],
-     mosi: [PA7, PB5,]);
+// alternate functions from the datasheet
+pins! {
+    PA5: (SckPin, SPI1, AF5),
+    PB3: (SckPin, SPI1, AF5),
+    PA6: (MisoPin, SPI1, AF5),
+    PB4: (MisoPin, SPI1, AF5),
+    PA7: (MosiPin, SPI1, AF5),
+    PB5: (MosiPin, SPI1, AF5),
+    PB13: (SckPin, SPI2, AF5),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+    PF9: (SckPin, SPI2, AF5),
+    #[cfg(not(any(feature = "lqfp48", feature = "lqfp64")))]
+    PF10: (SckPin, SPI2, AF5),
+    PA10: (MisoPin, SPI2, AF5),
+    PB14: (MisoPin, SPI2, AF5),
+    PA11: (MosiPin, SPI2, AF5),
+    PB15: (MosiPin, SPI2, AF5),
+    PB3: (SckPin, SPI3, AF6),
+    #[cfg(not(feature = "lqfp48"))]
+    PC10: (SckPin, SPI3, AF6),
+    PB4: (MisoPin, SPI3, AF6),
+    #[cfg(not(feature = "lqfp48"))]
+    PC11: (MisoPin, SPI3, AF6),
+    PB5: (MosiPin, SPI3, AF6),
+    #[cfg(not(feature = "lqfp48"))]
+    PC12: (MosiPin, SPI3, AF6),
+}
 
-spi!(SPI2,
-     apb1enr,
-     apb1rstr,
-     spi2en,
-     spi2rst,
-     pclk1,
-     AF5,
-     HighSpeed,
-     sck: [PB13,],
-     miso: [PB14,],
-     mosi: [PB15,]);
-spi!(SPI3,
-     apb1enr,
-     apb1rstr,
-     spi3en,
-     spi3rst,
-     pclk1,
-     AF6,
-     HighSpeed,
-     sck: [PB3, PC10,],
-     miso: [PB4, PC11,],
-     mosi: [PB5, PC12,]);
+spi!(SPI1, apb2enr, apb2rstr, spi1en, spi1rst, pclk2);
+spi!(SPI2, apb1enr, apb1rstr, spi2en, spi2rst, pclk1);
+spi!(SPI3, apb1enr, apb1rstr, spi3en, spi3rst, pclk1);
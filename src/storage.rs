@@ -1,207 +1,570 @@
-//! Storage 
+//! Storage
 
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::{cmp, ptr};
+
+use cast::u32;
 use hal::storage::*;
-use crate::pac::{FLASH};
 use nb;
-use cortex_m::asm;
 
-enum WriteMode {
-    EraseStart,
-    EraseEnd,
-    WriteStart,
-    WriteEnd
+use crate::flash::{KEY1, KEY2, PROG};
+use crate::pac::{Interrupt, FLASH};
+use crate::rcc;
+use crate::signature;
+
+const FLASH_START: u32 = 0x0800_0000;
+/// All F302/F303 size variants use 2 KiB pages
+const PAGE_SIZE: u32 = 2048;
+/// Outcome of the last operation as seen by the interrupt handler
+static OUTCOME: AtomicU8 = AtomicU8::new(PENDING);
+const PENDING: u8 = 0;
+const DONE: u8 = 1;
+const PROGRAMMING_ERROR: u8 = 2;
+const WRITE_PROTECTION_ERROR: u8 = 3;
+/// Polls of BSY, well above a page erase (40 ms max.) at 72 MHz
+const BSY_TIMEOUT: u32 = 5_000_000;
+/// Largest flash memory of the selected device
+#[cfg(feature = "stm32f303xe")]
+const MAX_SIZE: u32 = 512 * 1024;
+#[cfg(all(feature = "stm32f303xd", not(feature = "stm32f303xe")))]
+const MAX_SIZE: u32 = 384 * 1024;
+#[cfg(not(any(feature = "stm32f303xd", feature = "stm32f303xe")))]
+const MAX_SIZE: u32 = 256 * 1024;
+
+/// Flash programming error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlashError {
+    /// A half-word was programmed that wasn't erased before (PGERR)
+    Programming,
+    /// The page is write protected (WRPRTERR)
+    WriteProtection,
+    /// The address isn't aligned to a half-word
+    Alignment,
+    /// The page or address is outside of the flash memory
+    OutOfRange,
+    /// A half-word holding other data would have to be erased first
+    NotErased,
+    /// Reading back a programmed half-word gave a different value
+    Verification,
+}
+
+/// Contiguous range of flash pages
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlashRegion {
+    first: u32,
+    count: u32,
+}
+
+impl FlashRegion {
+    /// Creates the region of `count` pages from page `first` on
+    ///
+    /// Pages outside of the flash memory of the chip are rejected by
+    /// [`Flash`](struct.Flash.html) later on.
+    pub const fn new(first: u32, count: u32) -> Self {
+        FlashRegion { first, count }
+    }
+
+    /// Returns the first page of the region
+    pub fn first_page(&self) -> Page<u32> {
+        Page(self.first)
+    }
+
+    /// Returns the number of pages in the region
+    pub fn len(&self) -> u32 {
+        self.count
+    }
+
+    /// Returns whether the region holds no pages
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the address of the first page
+    pub fn start_address(&self) -> Address<u32> {
+        Address(FLASH_START + self.first * PAGE_SIZE)
+    }
+
+    /// Returns the size of the region in bytes
+    pub fn size(&self) -> AddressOffset<u32> {
+        AddressOffset(self.count * PAGE_SIZE)
+    }
+
+    /// Returns whether `page` is part of the region
+    pub fn contains(&self, page: &Page<u32>) -> bool {
+        page.0 >= self.first && page.0 - self.first < self.count
+    }
+
+    /// Returns whether `address` is inside of the region
+    pub fn contains_address(&self, address: &Address<u32>) -> bool {
+        let start = self.start_address().0;
+        address.0 >= start && address.0 - start < self.size().0
+    }
+
+    /// Returns whether the `len` bytes from `address` on are all inside of
+    /// the region
+    pub fn contains_span(&self, address: &Address<u32>, len: u32) -> bool {
+        let start = self.start_address().0;
+        match address.0.checked_sub(start) {
+            Some(offset) => {
+                offset <= self.size().0 && len <= self.size().0 - offset
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the last `n` pages, `None` if the region is smaller
+    pub fn last(&self, n: u32) -> Option<FlashRegion> {
+        if n > self.count {
+            return None;
+        }
+        Some(FlashRegion { first: self.first + self.count - n,
+                           count: n })
+    }
+
+    /// Iterates over the pages of the region
+    pub fn pages(&self) -> Pages {
+        Pages { next: self.first,
+                end: self.first + self.count }
+    }
+}
+
+/// Iterator over the pages of a [`FlashRegion`](struct.FlashRegion.html)
+pub struct Pages {
+    next: u32,
+    end: u32,
+}
+
+impl Iterator for Pages {
+    type Item = Page<u32>;
+
+    fn next(&mut self) -> Option<Page<u32>> {
+        if self.next < self.end {
+            self.next += 1;
+            Some(Page(self.next - 1))
+        } else {
+            None
+        }
+    }
 }
 
+/// Flash interrupt event
+pub enum Event {
+    /// An erase or a half-word programming finished (EOPIE)
+    EndOfOperation,
+    /// Programming or write protection error (ERRIE)
+    Error,
+}
+
+/// Records the outcome of a finished erase or programming
+///
+/// Call it from the `FLASH` interrupt handler after
+/// [`Flash::listen`](struct.Flash.html#method.listen) for both events. The
+/// operation itself continues at the next call of the `Flash` method that
+/// started it.
+pub fn flash_interrupt_handler() {
+    // NOTE(unsafe) SR flags are only cleared by the owner of `Flash` while
+    // the interrupts are disabled
+    let sr = unsafe { &(*FLASH::ptr()).sr };
+    let flags = sr.read();
+    let outcome = if flags.wrprterr().bit_is_set() {
+        WRITE_PROTECTION_ERROR
+    } else if flags.pgerr().bit_is_set() {
+        PROGRAMMING_ERROR
+    } else if flags.eop().bit_is_set() {
+        DONE
+    } else {
+        return;
+    };
+    // status flags are cleared by writing 1
+    sr.write(|w| w.eop().set_bit().wrprterr().set_bit().pgerr().set_bit());
+    OUTCOME.store(outcome, Ordering::Release);
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Idle,
+    Erase,
+    /// Programming, index of the next half-word
+    Program(usize),
+}
+
+/// Embedded flash memory
+///
+/// Erasing and programming are non-blocking, the same call has to be
+/// repeated until it no longer returns `WouldBlock`.
 pub struct Flash {
-    flash: FLASH,
-    write_mode: Option<WriteMode>
+    prog: PROG,
+    operation: Operation,
+}
+
+impl Flash {
+    /// Takes over the programming registers of the constrained FLASH
+    pub fn new(prog: PROG) -> Self {
+        Flash { prog,
+                operation: Operation::Idle }
+    }
+
+    /// Releases the programming registers
+    pub fn free(self) -> PROG {
+        self.prog
+    }
+
+    /// Returns all pages of the flash memory of this chip
+    pub fn region(&self) -> FlashRegion {
+        let kib = signature::flash_size_kib();
+        let size = cmp::min(u32(kib) * 1024, MAX_SIZE);
+        FlashRegion { first: 0,
+                      count: size / PAGE_SIZE }
+    }
+
+    /// Returns the last `n` pages of the flash memory, e.g. for settings
+    pub fn last_pages(&self, n: u32) -> Option<FlashRegion> {
+        self.region().last(n)
+    }
+
+    /// Returns associated interrupt
+    pub fn get_interrupt(&self) -> Interrupt {
+        Interrupt::FLASH
+    }
+
+    /// Starts listening for an interrupt event
+    pub fn listen(&mut self, event: Event) {
+        match event {
+            Event::EndOfOperation => {
+                self.prog.cr().modify(|_, w| w.eopie().set_bit())
+            }
+            Event::Error => self.prog.cr().modify(|_, w| w.errie().set_bit()),
+        }
+    }
+
+    /// Stops listening for an interrupt event
+    pub fn unlisten(&mut self, event: Event) {
+        match event {
+            Event::EndOfOperation => {
+                self.prog.cr().modify(|_, w| w.eopie().clear_bit())
+            }
+            Event::Error => {
+                self.prog.cr().modify(|_, w| w.errie().clear_bit())
+            }
+        }
+    }
+
+    /// Returns whether the interrupt handler saw the current erase or
+    /// half-word programming finish
+    ///
+    /// Calling the method that started the operation again then makes
+    /// progress without blocking.
+    pub fn is_done(&self) -> bool {
+        OUTCOME.load(Ordering::Acquire) != PENDING
+    }
+
+    fn unlock(&mut self) {
+        OUTCOME.store(PENDING, Ordering::Release);
+        // a wrong key sequence locks the FPEC until reset
+        if self.prog.cr().read().lock().bit_is_set() {
+            self.prog.keyr().write(|w| unsafe { w.bits(KEY1) });
+            self.prog.keyr().write(|w| unsafe { w.bits(KEY2) });
+        }
+    }
+
+    /// Waits for an operation of a previous owner and leaves programming
+    ///
+    /// Returns `false`, leaving the registers alone, if the operation
+    /// doesn't finish in time.
+    pub(crate) fn abort(&mut self) -> bool {
+        if !rcc::wait_ready(BSY_TIMEOUT, || {
+                               self.prog.sr().read().bsy().bit_is_clear()
+                           })
+        {
+            return false;
+        }
+        self.prog.sr().write(|w| {
+                          w.eop()
+                           .set_bit()
+                           .wrprterr()
+                           .set_bit()
+                           .pgerr()
+                           .set_bit()
+                      });
+        self.finish();
+        true
+    }
+
+    /// Rejects the `len` bytes from `address` on unless they are all inside
+    /// of the flash memory
+    fn check_span(&self,
+                  address: Address<u32>,
+                  len: u32)
+                  -> nb::Result<(), FlashError> {
+        if self.region().contains_span(&address, len) {
+            Ok(())
+        } else {
+            Err(nb::Error::Other(FlashError::OutOfRange))
+        }
+    }
+
+    fn finish(&mut self) {
+        self.prog.cr().modify(|_, w| {
+                          w.pg()
+                           .clear_bit()
+                           .per()
+                           .clear_bit()
+                           .lock()
+                           .set_bit()
+                      });
+        self.operation = Operation::Idle;
+    }
+
+    /// Waits for the current operation and clears its status flags
+    fn status(&mut self) -> nb::Result<(), FlashError> {
+        if self.prog.cr().read().eopie().bit_is_set() {
+            // the interrupt handler owns the status flags
+            let result = match OUTCOME.swap(PENDING, Ordering::AcqRel) {
+                PENDING => return Err(nb::Error::WouldBlock),
+                WRITE_PROTECTION_ERROR => Err(FlashError::WriteProtection),
+                PROGRAMMING_ERROR => Err(FlashError::Programming),
+                _ => Ok(()),
+            };
+            return result.map_err(|e| {
+                                     self.finish();
+                                     nb::Error::Other(e)
+                                 });
+        }
+
+        let sr = self.prog.sr().read();
+        if sr.bsy().bit_is_set() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let result = if sr.wrprterr().bit_is_set() {
+            Err(FlashError::WriteProtection)
+        } else if sr.pgerr().bit_is_set() {
+            Err(FlashError::Programming)
+        } else {
+            Ok(())
+        };
+        // status flags are cleared by writing 1
+        self.prog.sr().write(|w| {
+                          w.eop()
+                           .set_bit()
+                           .wrprterr()
+                           .set_bit()
+                           .pgerr()
+                           .set_bit()
+                      });
+
+        result.map_err(|e| {
+                          self.finish();
+                          nb::Error::Other(e)
+                      })
+    }
 }
 
-pub enum FlashError {}
+impl SingleWrite<u16, u32> for Flash {
+    type Error = FlashError;
 
-impl SingleWrite<u16,u32> for Flash 
-{
+    fn try_write(&mut self,
+                 address: Address<u32>,
+                 word: u16)
+                 -> nb::Result<(), Self::Error> {
+        let mut buf = [word];
+        self.try_write_slice(address, &mut buf)
+    }
+}
+
+impl MultiWrite<u16, u32> for Flash {
     type Error = FlashError;
-    fn try_write(&mut self, address: Address<u32>, word: u16) -> nb::Result<(), Self::Error> {
-        let mut buf = [word]; 
-        self.try_write_slice(address,&mut buf)?;
-        Ok(())
+
+    fn try_write_slice(&mut self,
+                       address: Address<u32>,
+                       buf: &mut [u16])
+                       -> nb::Result<(), Self::Error> {
+        if address.0 % 2 != 0 {
+            return Err(nb::Error::Other(FlashError::Alignment));
+        }
+
+        let index = match self.operation {
+            Operation::Idle => {
+                self.check_span(address, 2 * buf.len() as u32)?;
+                self.unlock();
+                self.prog.cr().modify(|_, w| w.pg().set_bit());
+                0
+            }
+            Operation::Program(index) => {
+                self.status()?;
+                index
+            }
+            // an erase has to be polled to completion first
+            Operation::Erase => return Err(nb::Error::WouldBlock),
+        };
+
+        if index == buf.len() {
+            self.finish();
+            return Ok(());
+        }
+
+        // NOTE(unsafe) half-word aligned write with PG set, the flash
+        // controller stalls the bus until the half-word is latched
+        unsafe {
+            ptr::write_volatile((address.0 as *mut u16).add(index), buf[index]);
+        }
+        self.operation = Operation::Program(index + 1);
+        Err(nb::Error::WouldBlock)
     }
 }
 
-impl MultiWrite<u16,u32> for Flash
-{
+/// Half-word at `halfword` with the bytes of `buf`, placed at `address`,
+/// merged into its `current` contents
+fn merge(halfword: u32, current: u16, address: u32, buf: &[u8]) -> u16 {
+    let end = address + buf.len() as u32;
+    let mut bytes = current.to_le_bytes();
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let addr = halfword + i as u32;
+        if addr >= address && addr < end {
+            *byte = buf[(addr - address) as usize];
+        }
+    }
+    u16::from_le_bytes(bytes)
+}
+
+fn read_halfword(address: u32) -> u16 {
+    // NOTE(unsafe) flash memory is always readable
+    unsafe { ptr::read_volatile(address as *const u16) }
+}
+
+/// Writes bytes at any address, merging with the neighbouring contents of
+/// the half-words they share
+impl MultiWrite<u8, u32> for Flash {
     type Error = FlashError;
-    fn try_write_slice(&mut self, address: Address<u32>, buf: &mut [u16]) -> nb::Result<(), Self::Error> {
-        use WriteMode::*;
-        match self.write_mode {
-            Some(EraseStart) | Some(EraseEnd) => {
-                self.try_erase_address(address)?;
-                self.write_mode = Some(WriteStart);
-                
-                Err(nb::Error::WouldBlock)
-            },
-            Some(WriteStart) => {
-                if self.flash.sr.read().bsy().bit_is_set() {
-                    return Err(nb::Error::WouldBlock);
-                }
-                unsafe {
-                    self.flash.keyr.write(|w| {
-                        w.bits(0x45670123)
-                    });
-                    self.flash.keyr.write(|w| {
-                        w.bits(0xCDEF89AB)
-                    });
-        
-                    self.flash.cr.write(|w| {
-                        w.pg().set_bit()
-                    });
-                }
-                
-                let mut start_address = address.0 as *mut _;
-                
-                for item in buf {
-                    unsafe {
-                        use core::ptr;
-                        ptr::write_volatile(start_address, *item);
-
-                        //16 bit word, but 8 byte addressing
-                        start_address = start_address.offset(2);
+
+    fn try_write_slice(&mut self,
+                       address: Address<u32>,
+                       buf: &mut [u8])
+                       -> nb::Result<(), Self::Error> {
+        let start = address.0 & !1;
+        let end = address.0 + buf.len() as u32;
+        let count = ((end - start + 1) / 2) as usize;
+        let halfword = |index: usize| start + 2 * index as u32;
+        let merged = |index: usize| {
+            let addr = halfword(index);
+            merge(addr, read_halfword(addr), address.0, buf)
+        };
+
+        let mut index = match self.operation {
+            Operation::Idle => {
+                // refuse before anything is read or programmed
+                self.check_span(address, buf.len() as u32)?;
+                for index in 0..count {
+                    let current = read_halfword(halfword(index));
+                    if current != 0xFFFF && current != merged(index) {
+                        return Err(nb::Error::Other(FlashError::NotErased));
                     }
-                    
                 }
-                
-                
-                asm::nop();
-                self.write_mode = Some(WriteEnd);
-                Err(nb::Error::WouldBlock)
-            },
-            Some(WriteEnd) => {
-                if self.flash.sr.read().bsy().bit_is_set() {
-                    return Err(nb::Error::WouldBlock);
+                self.unlock();
+                self.prog.cr().modify(|_, w| w.pg().set_bit());
+                0
+            }
+            Operation::Program(index) => {
+                self.status()?;
+                let previous = halfword(index - 1);
+                if read_halfword(previous) != merged(index - 1) {
+                    self.finish();
+                    return Err(nb::Error::Other(FlashError::Verification));
                 }
-                    
-                //unsafe {
-        
-                    self.flash.sr.write(|w| {
-                        w.eop().clear_bit()
-                    });
-        
-                    self.flash.cr.write(|w| {
-                        w.pg().clear_bit();
-                        w.lock().set_bit()
-                    });
-        
-                //}
-                self.write_mode = None;
-                Ok(())
-            },
-            None => Ok(())
+                index
+            }
+            // an erase has to be polled to completion first
+            Operation::Erase => return Err(nb::Error::WouldBlock),
+        };
+
+        // half-words that already hold their value are left alone
+        while index < count
+              && read_halfword(halfword(index)) == merged(index)
+        {
+            index += 1;
+        }
+        if index == count {
+            self.finish();
+            return Ok(());
         }
 
+        // NOTE(unsafe) half-word aligned write with PG set
+        unsafe {
+            ptr::write_volatile(halfword(index) as *mut u16, merged(index));
+        }
+        self.operation = Operation::Program(index + 1);
+        Err(nb::Error::WouldBlock)
     }
 }
 
-
-impl ErasePage<u32> for Flash 
-{
+impl ErasePage<u32> for Flash {
     type Error = FlashError;
-    fn try_erase_page(&mut self, page: Page<u32>) -> nb::Result<(), Self::Error> {
-        //convert the page ID to an address
-        let address = page.0*self.try_page_size(Address(0))?.0+self.try_start_address()?.0;
-        let address = Address(address);
-
-        self.try_erase_address(address)
-    }
-
-    fn try_erase_address(&mut self, address: Address<u32>) -> nb::Result<(), Self::Error> {
-        use WriteMode::*;
-        match self.write_mode {
-            Some(EraseStart) => {
-                unsafe {
-
-                    self.flash.keyr.write(|w| {
-                        w.bits(0x45670123)
-                    });
-                    self.flash.keyr.write(|w| {
-                        w.bits(0xCDEF89AB)
-                    });
-            
-                    self.flash.cr.write(|w| {
-                        //w.per().set_bit()
-                        w.bits(0x2)
-                    });
-            
-                    self.flash.ar.write(|w| {
-                        w.bits(address.0)
-                    });
-            
-                    self.flash.cr.write(|w| {
-                        w.per().set_bit();
-                        w.strt().set_bit()
-                    });
-            
-                    asm::nop();
+
+    fn try_erase_page(&mut self,
+                      page: Page<u32>)
+                      -> nb::Result<(), Self::Error> {
+        if !self.region().contains(&page) {
+            return Err(nb::Error::Other(FlashError::OutOfRange));
+        }
+        // convert the page ID to an address
+        let address = page.0 * PAGE_SIZE + FLASH_START;
+
+        self.try_erase_address(Address(address))
+    }
+
+    fn try_erase_address(&mut self,
+                         address: Address<u32>)
+                         -> nb::Result<(), Self::Error> {
+        match self.operation {
+            Operation::Idle => {
+                if !self.region().contains_address(&address) {
+                    return Err(nb::Error::Other(FlashError::OutOfRange));
                 }
-                self.write_mode = Some(EraseEnd);
+                self.unlock();
+                self.prog.cr().modify(|_, w| w.per().set_bit());
+                self.prog.ar().write(|w| unsafe { w.bits(address.0) });
+                self.prog.cr().modify(|_, w| w.strt().set_bit());
+                self.operation = Operation::Erase;
                 Err(nb::Error::WouldBlock)
-            },
-            Some(EraseEnd) => {
-                if self.flash.sr.read().bsy().bit_is_set() {
-                    return Err(nb::Error::WouldBlock);
-                }
-                
-                if self.flash.sr.read().eop().bit_is_set() {
-
-                    self.flash.sr.write(|w| {
-                        w.eop().clear_bit()
-                    });
-        
-                    self.flash.cr.write(|w| {
-                        w.per().clear_bit()
-                    });
-                    
-                }
-
-                self.flash.cr.write(|w| {
-                        w.lock().set_bit()
-                    });
-        
-        
-                self.write_mode = None;
-                Ok(())
-            },
-            //Other modes are not relevant so will be ignored
-            _ => {
+            }
+            Operation::Erase => {
+                self.status()?;
+                self.finish();
                 Ok(())
             }
+            // programming has to be polled to completion first
+            Operation::Program(_) => Err(nb::Error::WouldBlock),
         }
     }
-
 }
 
-impl SingleRead<u8,u32> for Flash 
-{
+impl SingleRead<u8, u32> for Flash {
     type Error = FlashError;
-    fn try_read(&mut self, address: Address<u32>) -> nb::Result<u8, Self::Error> {
-        let mut buf = [0]; 
-        self.try_read_slice(address,&mut buf)?;
+
+    fn try_read(&mut self,
+                address: Address<u32>)
+                -> nb::Result<u8, Self::Error> {
+        let mut buf = [0];
+        self.try_read_slice(address, &mut buf)?;
         Ok(buf[0])
     }
 }
 
-#[allow(unused_assignments)]
-impl MultiRead<u8,u32> for Flash
-{
+impl MultiRead<u8, u32> for Flash {
     type Error = FlashError;
-    fn try_read_slice(&mut self, address: Address<u32>, mut buf: &mut [u8]) -> nb::Result<(), Self::Error> {
-        let address = address.0 as *mut _;
-        unsafe {
-            
-            buf = core::slice::from_raw_parts_mut::<'static, u8>(address,buf.len());
+
+    fn try_read_slice(&mut self,
+                      address: Address<u32>,
+                      buf: &mut [u8])
+                      -> nb::Result<(), Self::Error> {
+        self.check_span(address, buf.len() as u32)?;
+        let start = address.0 as *const u8;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            // NOTE(unsafe) flash memory is always readable
+            *byte = unsafe { ptr::read_volatile(start.add(i)) };
         }
-        
-        Ok(()) 
+
+        Ok(())
     }
 }
 
@@ -209,15 +572,63 @@ impl StorageSize<u8, u32> for Flash {
     type Error = FlashError;
 
     fn try_start_address(&mut self) -> nb::Result<Address<u32>, Self::Error> {
-        Ok(Address(0x0800_0000))
+        Ok(Address(FLASH_START))
     }
 
-    fn try_total_size(&mut self) -> nb::Result<AddressOffset<u32>, Self::Error> {
-        Ok(AddressOffset(0))
+    fn try_total_size(&mut self)
+                      -> nb::Result<AddressOffset<u32>, Self::Error> {
+        Ok(self.region().size())
     }
 
     /// 2KB
-    fn try_page_size(&mut self, _address: Address<u32>) -> nb::Result<AddressOffset<u32>, Self::Error> {
-        Ok(AddressOffset(2048))
+    fn try_page_size(&mut self,
+                     _address: Address<u32>)
+                     -> nb::Result<AddressOffset<u32>, Self::Error> {
+        Ok(AddressOffset(PAGE_SIZE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge;
+
+    #[test]
+    fn merge_odd_start() {
+        // byte at 0x11 goes into the high half of the half-word at 0x10
+        assert_eq!(merge(0x10, 0xFFFF, 0x11, &[0xAB, 0xCD]), 0xABFF);
+        assert_eq!(merge(0x12, 0xFFFF, 0x11, &[0xAB, 0xCD]), 0xFFCD);
+    }
+
+    #[test]
+    fn merge_odd_end() {
+        assert_eq!(merge(0x10, 0xFFFF, 0x10, &[0x12, 0x34, 0x56]), 0x3412);
+        assert_eq!(merge(0x12, 0xFFFF, 0x10, &[0x12, 0x34, 0x56]), 0xFF56);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn merge_single_byte() {
+        assert_eq!(merge(0x10, 0xFFFF, 0x10, &[0x5A]), 0xFF5A);
+        assert_eq!(merge(0x10, 0xFFFF, 0x11, &[0x5A]), 0x5AFF);
+    }
+
+    #[test]
+    fn merge_keeps_neighbour() {
+        // the other byte of the half-word was programmed before
+        assert_eq!(merge(0x10, 0xFF12, 0x11, &[0x34]), 0x3412);
+    }
+
+    #[test]
+    fn merge_same_value() {
+        assert_eq!(merge(0x10, 0x3412, 0x10, &[0x12, 0x34]), 0x3412);
+    }
+
+    #[test]
+    fn merge_conflicting_byte() {
+        // the result differs from a programmed half-word, which
+        // `try_write_slice` refuses with `NotErased`
+        let current = 0x3412;
+        let merged = merge(0x10, current, 0x11, &[0x56]);
+        assert_eq!(merged, 0x5612);
+        assert!(current != 0xFFFF && current != merged);
+    }
+}
@@ -1,6 +1,8 @@
 //! Timers
 
-use crate::pac::{TIM2, TIM3, TIM4};
+#[cfg(feature = "stm32f303")]
+use crate::pac::TIM8;
+use crate::pac::{TIM1, TIM15, TIM16, TIM17, TIM2, TIM3, TIM4};
 use bobbin_bits::*;
 use cast::{u16, u32};
 use core::intrinsics::transmute;
@@ -10,7 +12,7 @@ use hal::timer::{CountDown, Periodic};
 use nb;
 use void::Void;
 
-use crate::rcc::Clocks;
+use crate::rcc::{Clocks, Reclock};
 use crate::time::Hertz;
 
 #[doc(hidden)]
@@ -31,6 +33,21 @@ pub enum Event {
     TimeOut,
 }
 
+/// Error reported when a timer can't follow a clock change
+#[derive(Debug)]
+pub enum ReclockError {
+    /// The timeout can't be derived from the new timer clock
+    Frequency,
+}
+
+/// Prescaler and auto-reload values for `frequency` at `timclk`
+fn psc_arr(timclk: u32, frequency: u32) -> Option<(u16, u16)> {
+    let ticks = timclk / frequency;
+    let psc = u16(ticks.checked_sub(1)? / (1 << 16)).ok()?;
+    let arr = u16(ticks / (u32(psc) + 1)).ok()?;
+    Some((psc, arr))
+}
+
 /// Timer channel
 pub trait TimerChannel {
     /// Enable channel
@@ -48,6 +65,15 @@ pub trait TimerChannel {
 /// System timer
 pub mod syst {
     use super::*;
+
+    /// SysTick reload value for `timeout` at `sysclk`
+    fn reload(sysclk: u32, timeout: u32) -> Option<u32> {
+        match (sysclk / timeout).checked_sub(1) {
+            Some(rvr) if rvr < (1 << 24) => Some(rvr),
+            _ => None,
+        }
+    }
+
     /// System timer
     pub struct Timer {
         clocks: Clocks,
@@ -71,9 +97,8 @@ pub mod syst {
         /// Resets timeout
         pub fn reset<T: Into<Hertz<u32>>>(&mut self, timeout: T) {
             self.timeout = timeout.into();
-            let rvr = self.clocks.sysclk().0 / self.timeout.0 - 1;
-
-            assert!(rvr < (1 << 24));
+            let rvr = reload(self.clocks.sysclk().0, self.timeout.0);
+            let rvr = rvr.expect("timeout out of range");
 
             self.tim.set_reload(rvr);
             self.tim.clear_current();
@@ -95,25 +120,41 @@ pub mod syst {
     }
 
     impl CountDown for Timer {
+        type Error = Void;
         type Time = Hertz<u32>;
 
-        fn try_start<T>(&mut self, timeout: T) -> Result<(), Self::Error> 
+        fn try_start<T>(&mut self, timeout: T) -> Result<(), Self::Error>
             where T: Into<Self::Time>
         {
             self.reset(timeout);
             self.tim.enable_counter();
+            Ok(())
         }
 
-        fn try_wait(&mut self) -> Result<(), Self::Error>  {
+        fn try_wait(&mut self) -> nb::Result<(), Self::Error> {
             if self.tim.has_wrapped() {
                 Ok(())
             } else {
-                Err(nb::Error::WouldBlock.into())
+                Err(nb::Error::WouldBlock)
             }
         }
     }
 
     impl Periodic for Timer {}
+
+    impl Reclock for Timer {
+        type Error = ReclockError;
+
+        fn reclock(&mut self, clocks: Clocks) -> Result<(), Self::Error> {
+            if reload(clocks.sysclk().0, self.timeout.0).is_none() {
+                return Err(ReclockError::Frequency);
+            }
+            self.clocks = clocks;
+            let timeout = self.timeout;
+            self.reset(timeout);
+            Ok(())
+        }
+    }
 }
 
 /// Trait for channel state
@@ -262,8 +303,12 @@ impl ChNum for CH4 {
 }
 
 macro_rules! tim {
-    ($TIMSRC:ident, $apb:ident, $timmod:ident, $timXen:ident, $timXrst:ident) => {
+    ($TIMSRC:ident, $apb:ident, $timmod:ident, $timXen:ident, $timXrst:ident, $timclk:ident,
+     [$(($CHX:ident, $chbits:ident, $ccrX:ident, $ccmrX:ident, $ocXm:ident, $MX:ident)),+]
+     $(, $bdtr:ident)*) => {
         /// $TIMSRC impl
+        // timers with fewer than four channels leave channel numbers unmatched
+        #[allow(unreachable_patterns)]
         pub mod $timmod {
             use super::*;
             use crate::rcc;
@@ -290,10 +335,8 @@ macro_rules! tim {
                     let tim = unsafe { &(*$TIMSRC::ptr()) };
                     unsafe {
                         match index {
-                            U2::B00 => tim.ccr1.write(|w| w.bits(value)),
-                            U2::B01 => tim.ccr2.write(|w| w.bits(value)),
-                            U2::B10 => tim.ccr3.write(|w| w.bits(value)),
-                            U2::B11 => tim.ccr4.write(|w| w.bits(value)),
+                            $(U2::$chbits => tim.$ccrX.write(|w| w.bits(value)),)+
+                            _ => unreachable!(),
                         }
                     }
                 }
@@ -302,10 +345,8 @@ macro_rules! tim {
                     let index = CN::channel_number();
                     let tim = unsafe { &(*$TIMSRC::ptr()) };
                     match index {
-                        U2::B00 => tim.ccr1.read().bits(),
-                        U2::B01 => tim.ccr2.read().bits(),
-                        U2::B10 => tim.ccr3.read().bits(),
-                        U2::B11 => tim.ccr4.read().bits(),
+                        $(U2::$chbits => tim.$ccrX.read().bits(),)+
+                        _ => unreachable!(),
                     }
                 }
 
@@ -334,14 +375,9 @@ macro_rules! tim {
                     let tim = unsafe { &(*$TIMSRC::ptr()) };
                     let mode_bits: u8 = nm.channel_mode().into();
                     match CN::channel_number() {
-                        U2::B00 => tim.ccmr1_output()
-                                      .modify(|_, w| w.oc1m().bits(mode_bits)),
-                        U2::B01 => tim.ccmr1_output()
-                                      .modify(|_, w| w.oc2m().bits(mode_bits)),
-                        U2::B10 => tim.ccmr2_output()
-                                      .modify(|_, w| w.oc3m().bits(mode_bits)),
-                        U2::B11 => tim.ccmr2_output()
-                                      .modify(|_, w| w.oc4m().bits(mode_bits)),
+                        $(U2::$chbits => tim.$ccmrX()
+                                            .modify(|_, w| w.$ocXm().bits(mode_bits)),)+
+                        _ => unreachable!(),
                     }
 
                     unsafe { transmute(self) }
@@ -352,27 +388,19 @@ macro_rules! tim {
                     let index: u32 = CN::channel_number().into();
                     let tim = unsafe { &(*$TIMSRC::ptr()) };
                     let mask = true;
-                    if index < 2 {
-                        let offset: u32 = 3 + (index * 4);
-                        tim.ccmr1_output().modify(|r, w| unsafe {
-                                              w.bits((r.bits()
-                                                      & !((mask as u32)
-                                                          << offset))
-                                                     | (((value & mask)
-                                                         as u32)
-                                                        << offset))
-                                          })
-                    } else {
-                        let offset: u32 = 3 + (index - 2) * 4;
-                        tim.ccmr2_output().modify(|r, w| unsafe {
+                    // OCxPE of odd channels in the upper half of CCMRx
+                    let offset: u32 = 3 + (index % 2) * 4;
+                    match CN::channel_number() {
+                        $(U2::$chbits => tim.$ccmrX().modify(|r, w| unsafe {
                                               w.bits((r.bits()
                                                       & !((mask as u32)
                                                           << offset))
                                                      | (((value & mask)
                                                          as u32)
                                                         << offset))
-                                          })
-                    };
+                                          }),)+
+                        _ => unreachable!(),
+                    }
                 }
             }
 
@@ -424,16 +452,13 @@ macro_rules! tim {
                 pub fn reset<T>(&mut self, timeout: T)
                     where T: Into<Hertz<u32>>
                 {
+                    let timeout = timeout.into();
+                    let (psc, arr) = psc_arr(self.clocks.$timclk().0, timeout.0)
+                        .expect("timeout out of range");
                     self.tim.cr1.modify(|_, w| w.cen().clear_bit());
                     // restart counter
                     self.tim.cnt.reset();
-                    let timeout = timeout.into();
-                    let frequency = timeout.0;
-                    let mult = if self.clocks.ppre1() == 1 { 1 } else { 2 };
-                    let ticks = self.clocks.pclk1().0 * mult / frequency;
-                    let psc = u16((ticks - 1) / (1 << 16)).unwrap();
                     self.tim.psc.write(|w| unsafe { w.bits(u32(psc)) });
-                    let arr = u16(ticks / u32(psc + 1)).unwrap();
                     self.tim.arr.write(|w| unsafe { w.bits(u32(arr)) });
                     self.timeout = timeout;
                 }
@@ -467,17 +492,39 @@ macro_rules! tim {
 
             impl<PS: PwmState> Periodic for Timer<PS> {}
 
+            /// Keeps the timeout, PWM duty cycles have to be set again as
+            /// the auto-reload value changes with the prescaler
+            impl<PS: PwmState> Reclock for Timer<PS> {
+                type Error = ReclockError;
+
+                fn reclock(&mut self, clocks: Clocks) -> Result<(), Self::Error> {
+                    if psc_arr(clocks.$timclk().0, self.timeout.0).is_none() {
+                        return Err(ReclockError::Frequency);
+                    }
+                    let running = self.tim.cr1.read().cen().bit_is_set();
+                    self.clocks = clocks;
+                    let timeout = self.timeout;
+                    self.reset(timeout);
+                    if running {
+                        self.enable();
+                    }
+                    Ok(())
+                }
+            }
+
             impl<PS: PwmState> CountDown for Timer<PS> {
+                type Error = Void;
                 type Time = Hertz<u32>;
 
-                fn try_start<T>(&mut self, timeout: T) -> Result<(), Self::Error> 
+                fn try_start<T>(&mut self, timeout: T) -> Result<(), Self::Error>
                     where T: Into<Hertz<u32>>
                 {
                     self.reset(timeout);
                     self.enable();
+                    Ok(())
                 }
 
-                fn try_wait(&mut self) -> Result<(), Self::Error>  {
+                fn try_wait(&mut self) -> nb::Result<(), Self::Error> {
                     if self.tim.sr.read().uif().bit_is_clear() {
                         Err(nb::Error::WouldBlock)
                     } else {
@@ -490,46 +537,30 @@ macro_rules! tim {
             impl Timer<PwmFree> {
                 /// Consumes timer and returns pwm channels and timer without
                 /// them.
+                ///
+                /// Timers with a break input get their main output enabled.
                 pub fn use_pwm(
                     self)
-                    -> ((Channel<CH1, Inactive>,
-                         Channel<CH2, Inactive>,
-                         Channel<CH3, Inactive>,
-                         Channel<CH4, Inactive>),
+                    -> (($(Channel<$CHX, Inactive>,)+),
                         Timer<PwmTaken>) {
-                    let ch1: Channel<CH1, Inactive> =
-                        Channel { _index: PhantomData,
-                                  _mode: PhantomData };
-                    let ch2: Channel<CH2, Inactive> =
-                        Channel { _index: PhantomData,
-                                  _mode: PhantomData };
-                    let ch3: Channel<CH3, Inactive> =
-                        Channel { _index: PhantomData,
-                                  _mode: PhantomData };
-                    let ch4: Channel<CH4, Inactive> =
-                        Channel { _index: PhantomData,
-                                  _mode: PhantomData };
-
-                    ((ch1, ch2, ch3, ch4), unsafe { transmute(self) })
+                    $(self.tim.$bdtr.modify(|_, w| w.moe().set_bit());)*
+
+                    let channels = ($(Channel::<$CHX, Inactive> {
+                                         _index: PhantomData,
+                                         _mode: PhantomData
+                                     },)+);
+
+                    (channels, unsafe { transmute(self) })
                 }
             }
 
             impl Timer<PwmTaken> {
                 /// Returns pwm channels back.
-                pub fn return_pwm<M1, M2, M3, M4>(self,
-                                                  _channels: (Channel<CH1,
-                                                           M1>,
-                                                   Channel<CH2,
-                                                           M2>,
-                                                   Channel<CH3,
-                                                           M3>,
-                                                   Channel<CH4,
-                                                           M4>))
-                                                  -> Timer<PwmFree>
-                    where M1: ChMode,
-                          M2: ChMode,
-                          M3: ChMode,
-                          M4: ChMode
+                pub fn return_pwm<$($MX),+>(self,
+                                            _channels: ($(Channel<$CHX,
+                                                                 $MX>,)+))
+                                            -> Timer<PwmFree>
+                    where $($MX: ChMode),+
                 {
                     unsafe { transmute(self) }
                 }
@@ -538,10 +569,44 @@ macro_rules! tim {
     };
 }
 
-tim!(TIM2, APB1, tim2, tim2en, tim2rst);
-tim!(TIM3, APB1, tim3, tim3en, tim3rst);
-tim!(TIM4, APB1, tim4, tim4en, tim4rst);
-// TODO: add other timers with different number of channels
-// tim!(TIM6, APB1, tim6, tim6en, tim6rst);
-// tim!(TIM7, APB1, tim7, tim7en, tim7rst);
-// tim!(TIM8, APB2, tim8, tim8en, tim8rst);
+tim!(TIM2, APB1, tim2, tim2en, tim2rst, timclk1,
+     [(CH1, B00, ccr1, ccmr1_output, oc1m, M1),
+      (CH2, B01, ccr2, ccmr1_output, oc2m, M2),
+      (CH3, B10, ccr3, ccmr2_output, oc3m, M3),
+      (CH4, B11, ccr4, ccmr2_output, oc4m, M4)]);
+tim!(TIM3, APB1, tim3, tim3en, tim3rst, timclk1,
+     [(CH1, B00, ccr1, ccmr1_output, oc1m, M1),
+      (CH2, B01, ccr2, ccmr1_output, oc2m, M2),
+      (CH3, B10, ccr3, ccmr2_output, oc3m, M3),
+      (CH4, B11, ccr4, ccmr2_output, oc4m, M4)]);
+tim!(TIM4, APB1, tim4, tim4en, tim4rst, timclk1,
+     [(CH1, B00, ccr1, ccmr1_output, oc1m, M1),
+      (CH2, B01, ccr2, ccmr1_output, oc2m, M2),
+      (CH3, B10, ccr3, ccmr2_output, oc3m, M3),
+      (CH4, B11, ccr4, ccmr2_output, oc4m, M4)]);
+tim!(TIM1, APB2, tim1, tim1en, tim1rst, tim1clk,
+     [(CH1, B00, ccr1, ccmr1_output, oc1m, M1),
+      (CH2, B01, ccr2, ccmr1_output, oc2m, M2),
+      (CH3, B10, ccr3, ccmr2_output, oc3m, M3),
+      (CH4, B11, ccr4, ccmr2_output, oc4m, M4)],
+     bdtr);
+#[cfg(feature = "stm32f303")]
+tim!(TIM8, APB2, tim8, tim8en, tim8rst, tim8clk,
+     [(CH1, B00, ccr1, ccmr1_output, oc1m, M1),
+      (CH2, B01, ccr2, ccmr1_output, oc2m, M2),
+      (CH3, B10, ccr3, ccmr2_output, oc3m, M3),
+      (CH4, B11, ccr4, ccmr2_output, oc4m, M4)],
+     bdtr);
+tim!(TIM15, APB2, tim15, tim15en, tim15rst, timclk2,
+     [(CH1, B00, ccr1, ccmr1_output, oc1m, M1),
+      (CH2, B01, ccr2, ccmr1_output, oc2m, M2)],
+     bdtr);
+tim!(TIM16, APB2, tim16, tim16en, tim16rst, timclk2,
+     [(CH1, B00, ccr1, ccmr1_output, oc1m, M1)],
+     bdtr);
+tim!(TIM17, APB2, tim17, tim17en, tim17rst, timclk2,
+     [(CH1, B00, ccr1, ccmr1_output, oc1m, M1)],
+     bdtr);
+// TODO: basic timers without channels
+// tim!(TIM6, APB1, tim6, tim6en, tim6rst, timclk1);
+// tim!(TIM7, APB1, tim7, tim7en, tim7rst, timclk1);
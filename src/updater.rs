@@ -0,0 +1,575 @@
+//! Firmware updates into A/B image slots
+//!
+//! A new image is streamed into the slot that isn't running, checked with
+//! the hardware CRC unit and then selected by appending a boot record to a
+//! region of its own. A bootloader reads the record back with
+//! [`Updater::boot_record`](struct.Updater.html#method.boot_record) to pick
+//! the slot to jump to.
+//!
+//! The regions are page ranges of any storage implementing the
+//! `hal::storage` traits, [`storage::Flash`](../storage/struct.Flash.html) on
+//! the target.
+
+use hal::serial;
+use hal::storage::{Address, ErasePage, MultiRead, MultiWrite, StorageSize};
+use nb;
+
+use crate::crc::Crc;
+use crate::storage::FlashRegion;
+
+const RECORD_MAGIC: u32 = 0xB007_5E1E;
+const RECORD_SIZE: u32 = 16;
+/// Bytes collected from a source before they're programmed
+const CHUNK_SIZE: usize = 64;
+
+/// Image slot
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    /// Slot A
+    A,
+    /// Slot B
+    B,
+}
+
+impl Slot {
+    /// Returns the other slot
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn bits(self) -> u32 {
+        match self {
+            Slot::A => 0xA,
+            Slot::B => 0xB,
+        }
+    }
+}
+
+/// Boot selection record
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BootRecord {
+    /// Slot to boot from
+    pub slot: Slot,
+    /// Length of the image in bytes
+    pub len: u32,
+    /// CRC-32 of the image
+    pub crc: u32,
+}
+
+/// CRC-32 calculation the images are checked with
+///
+/// Implemented by the hardware [`Crc`](../crc/struct.Crc.html) unit.
+pub trait Checksum {
+    /// Starts a new calculation
+    fn reset(&mut self);
+
+    /// Feeds `data` into the calculation
+    fn feed(&mut self, data: &[u8]);
+
+    /// Returns the CRC-32 of the bytes fed since the last reset
+    fn result(&self) -> u32;
+}
+
+impl Checksum for Crc {
+    fn reset(&mut self) {
+        Crc::reset(self)
+    }
+
+    fn feed(&mut self, data: &[u8]) {
+        Crc::feed(self, data)
+    }
+
+    fn result(&self) -> u32 {
+        Crc::result(self)
+    }
+}
+
+/// Firmware update error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error<E> {
+    /// Error of the underlying storage
+    Storage(E),
+    /// The slots and the record region overlap
+    Overlap,
+    /// The image doesn't fit into its slot
+    TooLarge,
+    /// The image doesn't match the expected CRC
+    Crc,
+    /// The byte source failed
+    Source,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Storage(e)
+    }
+}
+
+fn overlaps(a: &FlashRegion, b: &FlashRegion) -> bool {
+    let (a_start, b_start) = (a.first_page().0, b.first_page().0);
+    a_start < b_start + b.len() && b_start < a_start + a.len()
+}
+
+fn word(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0])
+    | u32::from(bytes[1]) << 8
+    | u32::from(bytes[2]) << 16
+    | u32::from(bytes[3]) << 24
+}
+
+/// Image slots and boot record on the flash memory
+pub struct Updater<S, C> {
+    storage: S,
+    crc: C,
+    /// Address of page 0 of the storage
+    base: u32,
+    page_size: u32,
+    a: FlashRegion,
+    b: FlashRegion,
+    record: FlashRegion,
+}
+
+impl<S, C, E> Updater<S, C>
+    where S: MultiRead<u8, u32, Error = E>
+              + MultiWrite<u16, u32, Error = E>
+              + ErasePage<u32, Error = E>
+              + StorageSize<u8, u32, Error = E>,
+          C: Checksum
+{
+    /// Manages the image slots `a` and `b`, keeping boot records in `record`
+    pub fn new(mut storage: S,
+               crc: C,
+               a: FlashRegion,
+               b: FlashRegion,
+               record: FlashRegion)
+               -> Result<Self, Error<E>> {
+        if overlaps(&a, &b) || overlaps(&a, &record) || overlaps(&b, &record)
+        {
+            return Err(Error::Overlap);
+        }
+        let base = nb::block!(storage.try_start_address())?.0;
+        let page_size = nb::block!(storage.try_page_size(Address(base)))?.0;
+        Ok(Updater { storage,
+                     crc,
+                     base,
+                     page_size,
+                     a,
+                     b,
+                     record })
+    }
+
+    /// Releases the storage and the CRC unit
+    pub fn free(self) -> (S, C) {
+        (self.storage, self.crc)
+    }
+
+    /// Returns the pages of `slot`
+    pub fn region(&self, slot: Slot) -> FlashRegion {
+        match slot {
+            Slot::A => self.a,
+            Slot::B => self.b,
+        }
+    }
+
+    /// Returns the latest boot record, `None` if none was written yet
+    pub fn boot_record(&mut self) -> Result<Option<BootRecord>, Error<E>> {
+        let start = self.start(&self.record);
+        let size = self.size(&self.record);
+        let mut latest = None;
+        let mut offset = 0;
+        while offset + RECORD_SIZE <= size {
+            let mut buf = [0; RECORD_SIZE as usize];
+            nb::block!(self.storage
+                           .try_read_slice(Address(start + offset), &mut buf))?;
+            if buf.iter().all(|b| *b == 0xFF) {
+                break;
+            }
+            // records without magic were torn by a power loss
+            if word(&buf[0..4]) == RECORD_MAGIC {
+                let slot = if word(&buf[4..8]) == Slot::B.bits() {
+                    Slot::B
+                } else {
+                    Slot::A
+                };
+                latest = Some(BootRecord { slot,
+                                           len: word(&buf[8..12]),
+                                           crc: word(&buf[12..16]) });
+            }
+            offset += RECORD_SIZE;
+        }
+        Ok(latest)
+    }
+
+    /// Returns the slot selected by the latest boot record, A without one
+    pub fn active_slot(&mut self) -> Result<Slot, Error<E>> {
+        Ok(self.boot_record()?.map(|r| r.slot).unwrap_or(Slot::A))
+    }
+
+    /// Returns whether the image of `record` matches its CRC
+    pub fn verify(&mut self,
+                  record: &BootRecord)
+                  -> Result<bool, Error<E>> {
+        Ok(self.checksum(record.slot, record.len)? == record.crc)
+    }
+
+    /// Erases the inactive slot and starts writing a new image into it
+    pub fn begin(&mut self) -> Result<Update<'_, S, C>, Error<E>> {
+        let slot = self.active_slot()?.other();
+        for page in self.region(slot).pages() {
+            nb::block!(self.storage.try_erase_page(page))?;
+        }
+        Ok(Update { updater: self,
+                    slot,
+                    written: 0,
+                    pending: None })
+    }
+
+    /// CRC-32 of the first `len` bytes of `slot`
+    fn checksum(&mut self, slot: Slot, len: u32) -> Result<u32, Error<E>> {
+        let region = self.region(slot);
+        if len > self.size(&region) {
+            return Err(Error::TooLarge);
+        }
+        let start = self.start(&region);
+
+        self.crc.reset();
+        let mut offset = 0;
+        while offset < len {
+            let mut buf = [0; CHUNK_SIZE];
+            let n = core::cmp::min(CHUNK_SIZE as u32, len - offset) as usize;
+            nb::block!(self.storage.try_read_slice(Address(start + offset),
+                                                   &mut buf[..n]))?;
+            self.crc.feed(&buf[..n]);
+            offset += n as u32;
+        }
+        Ok(self.crc.result())
+    }
+
+    /// Appends `record`, starting over once the record region is full
+    fn write_record(&mut self, record: &BootRecord) -> Result<(), Error<E>> {
+        let start = self.start(&self.record);
+        let size = self.size(&self.record);
+        let mut offset = 0;
+        while offset + RECORD_SIZE <= size {
+            let mut buf = [0; RECORD_SIZE as usize];
+            nb::block!(self.storage
+                           .try_read_slice(Address(start + offset), &mut buf))?;
+            if buf.iter().all(|b| *b == 0xFF) {
+                break;
+            }
+            offset += RECORD_SIZE;
+        }
+        if offset + RECORD_SIZE > size {
+            for page in self.record.pages() {
+                nb::block!(self.storage.try_erase_page(page))?;
+            }
+            offset = 0;
+        }
+
+        let address = start + offset;
+        let mut fields = [0; 12];
+        fields[0..4].copy_from_slice(&record.slot.bits().to_le_bytes());
+        fields[4..8].copy_from_slice(&record.len.to_le_bytes());
+        fields[8..12].copy_from_slice(&record.crc.to_le_bytes());
+        self.program(address + 4, &fields)?;
+        // the record only counts once the magic is written
+        self.program(address, &RECORD_MAGIC.to_le_bytes())
+    }
+
+    /// Programs the even number of `bytes`, at most `CHUNK_SIZE`, at the
+    /// half-word aligned `address`
+    fn program(&mut self, address: u32, bytes: &[u8]) -> Result<(), Error<E>> {
+        let mut buf = [0; CHUNK_SIZE / 2];
+        let buf = &mut buf[..bytes.len() / 2];
+        for (halfword, pair) in buf.iter_mut().zip(bytes.chunks(2)) {
+            *halfword = u16::from(pair[0]) | u16::from(pair[1]) << 8;
+        }
+        nb::block!(self.storage.try_write_slice(Address(address), buf))?;
+        Ok(())
+    }
+
+    fn start(&self, region: &FlashRegion) -> u32 {
+        self.base + region.first_page().0 * self.page_size
+    }
+
+    fn size(&self, region: &FlashRegion) -> u32 {
+        region.len() * self.page_size
+    }
+}
+
+/// Image being written into a slot
+pub struct Update<'a, S, C> {
+    updater: &'a mut Updater<S, C>,
+    slot: Slot,
+    written: u32,
+    /// Odd trailing byte, flash is programmed in half-words
+    pending: Option<u8>,
+}
+
+impl<'a, S, C, E> Update<'a, S, C>
+    where S: MultiRead<u8, u32, Error = E>
+              + MultiWrite<u16, u32, Error = E>
+              + ErasePage<u32, Error = E>
+              + StorageSize<u8, u32, Error = E>,
+          C: Checksum
+{
+    /// Returns the slot the image is written to
+    pub fn slot(&self) -> Slot {
+        self.slot
+    }
+
+    /// Returns the number of bytes written so far
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    /// Appends `data` to the image
+    ///
+    /// An odd trailing byte is only programmed with the next write or by
+    /// [`finish`](#method.finish).
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error<E>> {
+        let region = self.updater.region(self.slot);
+        if self.written + data.len() as u32 > self.updater.size(&region) {
+            return Err(Error::TooLarge);
+        }
+
+        let mut data = data;
+        if let Some(first) = self.pending {
+            let second = match data.split_first() {
+                Some((second, rest)) => {
+                    data = rest;
+                    *second
+                }
+                None => return Ok(()),
+            };
+            self.program(self.written - 1, &[first, second])?;
+            self.pending = None;
+            self.written += 1;
+        }
+
+        let even = data.len() & !1;
+        for chunk in data[..even].chunks(CHUNK_SIZE) {
+            self.program(self.written, chunk)?;
+            self.written += chunk.len() as u32;
+        }
+        if even < data.len() {
+            self.pending = Some(data[even]);
+            self.written += 1;
+        }
+        Ok(())
+    }
+
+    /// Appends `len` bytes read from `source`, e.g. a serial port
+    pub fn write_from<R>(&mut self,
+                         source: &mut R,
+                         len: u32)
+                         -> Result<(), Error<E>>
+        where R: serial::Read<u8>
+    {
+        let mut buf = [0; CHUNK_SIZE];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = core::cmp::min(CHUNK_SIZE as u32, remaining) as usize;
+            for byte in buf[..n].iter_mut() {
+                *byte = nb::block!(source.try_read()).map_err(|_| {
+                                                           Error::Source
+                                                       })?;
+            }
+            self.write(&buf[..n])?;
+            remaining -= n as u32;
+        }
+        Ok(())
+    }
+
+    /// Checks the image against `crc` and selects it for the next boot
+    pub fn finish(mut self, crc: u32) -> Result<BootRecord, Error<E>> {
+        // pad the odd last byte, the record length leaves the padding out
+        if let Some(byte) = self.pending.take() {
+            self.program(self.written - 1, &[byte, 0xFF])?;
+        }
+
+        let record = BootRecord { slot: self.slot,
+                                  len: self.written,
+                                  crc };
+        if !self.updater.verify(&record)? {
+            return Err(Error::Crc);
+        }
+        self.updater.write_record(&record)?;
+        Ok(record)
+    }
+
+    /// Programs `bytes` at the even `offset` into the slot
+    fn program(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error<E>> {
+        let region = self.updater.region(self.slot);
+        let start = self.updater.start(&region);
+        self.updater.program(start + offset, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_flash::{MockError, MockFlash, PAGE_SIZE, START};
+
+    /// Bitwise CRC-32 of zlib, as set up on the CRC unit
+    struct SoftCrc(u32);
+
+    impl Checksum for SoftCrc {
+        fn reset(&mut self) {
+            self.0 = 0xFFFF_FFFF;
+        }
+
+        fn feed(&mut self, data: &[u8]) {
+            for byte in data {
+                self.0 ^= u32::from(*byte);
+                for _ in 0..8 {
+                    let mask = (self.0 & 1).wrapping_neg();
+                    self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+                }
+            }
+        }
+
+        fn result(&self) -> u32 {
+            !self.0
+        }
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = SoftCrc(0);
+        crc.reset();
+        crc.feed(data);
+        crc.result()
+    }
+
+    /// Slot A, slot B and the record region take a page each
+    fn updater(flash: MockFlash) -> Updater<MockFlash, SoftCrc> {
+        Updater::new(flash,
+                     SoftCrc(0),
+                     FlashRegion::new(0, 1),
+                     FlashRegion::new(1, 1),
+                     FlashRegion::new(2, 1)).unwrap()
+    }
+
+    fn memory(updater: &Updater<MockFlash, SoftCrc>,
+              region: FlashRegion)
+              -> &[u8] {
+        let start = (updater.start(&region) - START) as usize;
+        &updater.storage.memory[start..start + PAGE_SIZE as usize]
+    }
+
+    /// Writes `image` in one go and selects it
+    fn install(updater: &mut Updater<MockFlash, SoftCrc>,
+               image: &[u8])
+               -> BootRecord {
+        let mut update = updater.begin().unwrap();
+        update.write(image).unwrap();
+        update.finish(crc32(image)).unwrap()
+    }
+
+    #[test]
+    fn soft_crc_matches_zlib() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn odd_split_across_writes() {
+        let mut updater = updater(MockFlash::new());
+        let image = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut update = updater.begin().unwrap();
+        assert_eq!(update.slot(), Slot::B);
+        update.write(&image[..3]).unwrap();
+        update.write(&image[3..4]).unwrap();
+        update.write(&[]).unwrap();
+        update.write(&image[4..7]).unwrap();
+        update.write(&image[7..]).unwrap();
+        assert_eq!(update.written(), 8);
+        let record = update.finish(crc32(&image)).unwrap();
+
+        assert_eq!(record,
+                   BootRecord { slot: Slot::B,
+                                len: 8,
+                                crc: crc32(&image) });
+        assert_eq!(&memory(&updater, FlashRegion::new(1, 1))[..9],
+                   &[1, 2, 3, 4, 5, 6, 7, 8, 0xFF]);
+        assert_eq!(updater.boot_record().unwrap(), Some(record));
+        assert_eq!(updater.active_slot().unwrap(), Slot::B);
+    }
+
+    #[test]
+    fn finish_pads_odd_length() {
+        let mut updater = updater(MockFlash::new());
+        let image = [0x12, 0x34, 0x56];
+        let record = install(&mut updater, &image);
+
+        assert_eq!(record.len, 3);
+        assert_eq!(&memory(&updater, FlashRegion::new(1, 1))[..4],
+                   &[0x12, 0x34, 0x56, 0xFF]);
+        assert!(updater.verify(&record).unwrap());
+    }
+
+    #[test]
+    fn crc_mismatch_keeps_record() {
+        let mut updater = updater(MockFlash::new());
+        let mut update = updater.begin().unwrap();
+        update.write(&[1, 2]).unwrap();
+        assert_eq!(update.finish(0), Err(Error::Crc));
+        assert_eq!(updater.boot_record().unwrap(), None);
+    }
+
+    #[test]
+    fn too_large() {
+        let mut updater = updater(MockFlash::new());
+        let mut update = updater.begin().unwrap();
+        update.write(&[0; PAGE_SIZE as usize - 1]).unwrap();
+        assert_eq!(update.write(&[0; 2]), Err(Error::TooLarge));
+        update.write(&[0]).unwrap();
+        assert_eq!(update.write(&[0]), Err(Error::TooLarge));
+        assert_eq!(update.written(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn record_region_wraps() {
+        let mut updater = updater(MockFlash::new());
+        let per_page = PAGE_SIZE / RECORD_SIZE;
+        let mut last = None;
+        for i in 0..=per_page as u8 {
+            last = Some(install(&mut updater, &[i, i + 1]));
+        }
+        // the last record starts over on the erased record region
+        let record = memory(&updater, FlashRegion::new(2, 1));
+        assert_eq!(word(&record[0..4]), RECORD_MAGIC);
+        assert!(record[RECORD_SIZE as usize..].iter().all(|b| *b == 0xFF));
+        assert_eq!(updater.boot_record().unwrap(), last);
+        assert_eq!(updater.active_slot().unwrap(), Slot::B);
+    }
+
+    #[test]
+    fn torn_magic_is_ignored() {
+        let mut updater = updater(MockFlash::new());
+        let first = install(&mut updater, &[1, 2, 3, 4]);
+
+        let image = [5, 6, 7, 8];
+        let mut update = updater.begin().unwrap();
+        assert_eq!(update.slot(), Slot::A);
+        update.write(&image).unwrap();
+        // the power is cut after the fields, before the magic
+        update.updater.storage.budget = Some(6);
+        assert_eq!(update.finish(crc32(&image)),
+                   Err(Error::Storage(MockError::PowerLoss)));
+
+        let (mut flash, _) = updater.free();
+        flash.budget = None;
+        let mut updater = self::updater(flash);
+        assert_eq!(updater.boot_record().unwrap(), Some(first));
+
+        // the next record goes after the torn one
+        let second = install(&mut updater, &image);
+        assert_eq!(second.slot, Slot::A);
+        let record = memory(&updater, FlashRegion::new(2, 1));
+        assert_eq!(word(&record[2 * RECORD_SIZE as usize..]), RECORD_MAGIC);
+        assert_eq!(updater.boot_record().unwrap(), Some(second));
+    }
+}